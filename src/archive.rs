@@ -0,0 +1,69 @@
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::io::Read;
+
+use crate::error::{Result, StegError};
+
+// An archive is a u16 entry count, then one (name_len: u8, name, offset: u64,
+// length: u64) entry per file, then the file contents back to back. Offsets
+// are relative to the end of the table.
+pub(crate) fn pack(files: &[(String, Vec<u8>)]) -> Result<Vec<u8>> {
+    if files.len() > u16::MAX as usize {
+        return Err(StegError::InvalidArgument(format!(
+            "{} files exceed the archive limit of {}",
+            files.len(),
+            u16::MAX
+        )));
+    }
+
+    let mut out = Vec::new();
+    out.write_u16::<LE>(files.len() as u16)?;
+    let mut offset = 0u64;
+    for (name, data) in files {
+        if name.len() > u8::MAX as usize {
+            return Err(StegError::InvalidArgument(format!(
+                "filename of {} bytes exceeds limit {}",
+                name.len(),
+                u8::MAX
+            )));
+        }
+
+        out.write_u8(name.len() as u8)?;
+        out.extend_from_slice(name.as_bytes());
+        out.write_u64::<LE>(offset)?;
+        out.write_u64::<LE>(data.len() as u64)?;
+        offset += data.len() as u64;
+    }
+
+    for (_, data) in files {
+        out.extend_from_slice(data);
+    }
+    Ok(out)
+}
+
+pub(crate) fn unpack(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let corrupted = || StegError::Corrupted("corrupted file archive".into());
+
+    let mut rd = data;
+    let count = rd.read_u16::<LE>()?;
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut name = vec![0u8; rd.read_u8()? as usize];
+        rd.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| corrupted())?;
+        let offset = rd.read_u64::<LE>()?;
+        let length = rd.read_u64::<LE>()?;
+        entries.push((name, offset, length));
+    }
+
+    let body = rd;
+    entries
+        .into_iter()
+        .map(|(name, offset, length)| {
+            let end = offset.checked_add(length).ok_or_else(corrupted)?;
+            let contents = body
+                .get(offset as usize..end as usize)
+                .ok_or_else(corrupted)?;
+            Ok((name, contents.to_vec()))
+        })
+        .collect()
+}
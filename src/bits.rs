@@ -0,0 +1,529 @@
+use byteorder::{ReadBytesExt, WriteBytesExt};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::error::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitOrder {
+    #[default]
+    LsbFirst,
+    MsbFirst,
+}
+
+impl BitOrder {
+    fn shift(self, bit_position: u64) -> u64 {
+        match self {
+            BitOrder::LsbFirst => bit_position % 8,
+            BitOrder::MsbFirst => 7 - bit_position % 8,
+        }
+    }
+}
+
+pub struct BitReader<R> {
+    pub size: u64,
+    rd: R,
+    start: u64,
+    order: BitOrder,
+    bit_position: u64,
+    byte: u8,
+}
+
+pub type FileBitReader = BitReader<BufReader<File>>;
+
+impl FileBitReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_order(path, BitOrder::default())
+    }
+
+    pub fn open_with_order(path: &Path, order: BitOrder) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Self::with_order(BufReader::new(file), order)
+    }
+}
+
+pub type MemBitReader<'a> = BitReader<Cursor<&'a [u8]>>;
+
+impl<'a> MemBitReader<'a> {
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        Self::from_slice_with_order(data, BitOrder::default())
+    }
+
+    pub fn from_slice_with_order(data: &'a [u8], order: BitOrder) -> Self {
+        Self {
+            rd: Cursor::new(data),
+            size: data.len() as u64,
+            start: 0,
+            order,
+            bit_position: 0,
+            byte: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> BitReader<R> {
+    pub fn new(rd: R) -> Result<Self> {
+        Self::with_order(rd, BitOrder::default())
+    }
+
+    /// Reads from the current position of `rd` to its end; `size` is the
+    /// number of bytes remaining.
+    pub fn with_order(mut rd: R, order: BitOrder) -> Result<Self> {
+        let start = rd.stream_position()?;
+        let size = rd.seek(SeekFrom::End(0))? - start;
+        rd.seek(SeekFrom::Start(start))?;
+        let bit_position: u64 = 0;
+
+        Ok(Self {
+            rd,
+            size,
+            start,
+            order,
+            bit_position,
+            byte: 0,
+        })
+    }
+
+    pub fn read_bit(&mut self) -> Result<u8> {
+        if self.bit_position.is_multiple_of(8) {
+            self.byte = self.rd.read_u8()?;
+        }
+
+        let bit = (self.byte >> self.order.shift(self.bit_position)) & 1;
+        self.bit_position += 1;
+        Ok(bit)
+    }
+
+    pub fn seek_bit(&mut self, pos: u64) -> Result<()> {
+        self.rd.seek(SeekFrom::Start(self.start + pos / 8))?;
+        self.bit_position = pos;
+        // The cached byte belongs to the old position; a mid-byte target
+        // has to load its own byte since read_bit only fetches on boundaries.
+        if !pos.is_multiple_of(8) {
+            self.byte = self.rd.read_u8()?;
+        }
+        Ok(())
+    }
+
+    pub fn rewind(&mut self) -> Result<()> {
+        self.seek_bit(0)
+    }
+
+    pub fn read_bits(&mut self, len: u8) -> Result<u8> {
+        assert!(len > 0 && len <= 8);
+
+        let mut out: u8 = 0;
+        for i in 0..len {
+            out |= self.read_bit()? << i;
+        }
+        Ok(out)
+    }
+
+    pub fn read_bits_u32(&mut self, len: u8) -> Result<u32> {
+        assert!(len > 0 && len <= 32);
+
+        let mut out: u32 = 0;
+        for i in 0..len {
+            out |= (self.read_bit()? as u32) << i;
+        }
+        Ok(out)
+    }
+}
+
+pub struct BitWriter<W: Write> {
+    wd: W,
+    order: BitOrder,
+    bit_position: u64,
+    byte: Option<u8>,
+}
+
+pub type FileBitWriter = BitWriter<BufWriter<File>>;
+
+impl FileBitWriter {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_order(path, BitOrder::default())
+    }
+
+    pub fn open_with_order(path: &Path, order: BitOrder) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self::with_order(BufWriter::new(file), order))
+    }
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(wd: W) -> Self {
+        Self::with_order(wd, BitOrder::default())
+    }
+
+    pub fn with_order(wd: W, order: BitOrder) -> Self {
+        Self {
+            wd,
+            order,
+            bit_position: 0,
+            byte: None,
+        }
+    }
+
+    fn flush_byte(&mut self) -> Result<()> {
+        if let Some(byte) = self.byte {
+            self.wd.write_u8(byte)?;
+            self.byte = None;
+        }
+        Ok(())
+    }
+
+    /// Writes out any partial byte and flushes the underlying writer.
+    /// Dropping the writer does the same but has to discard errors.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush_byte()?;
+        self.wd.flush()?;
+        Ok(())
+    }
+
+    pub fn write_bit(&mut self, bit: u8) -> Result<()> {
+        let bit = bit << self.order.shift(self.bit_position);
+        self.byte = Some(self.byte.unwrap_or(0) | bit);
+
+        self.bit_position += 1;
+        if self.bit_position != 0 && self.bit_position.is_multiple_of(8) {
+            self.flush_byte()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_bits(&mut self, mut bits: u8, len: u8) -> Result<()> {
+        assert!(len > 0 && len <= 8);
+
+        for _ in 0..len {
+            self.write_bit(bits & 1)?;
+            bits >>= 1;
+        }
+        Ok(())
+    }
+
+    pub fn write_bits_u32(&mut self, mut bits: u32, len: u8) -> Result<()> {
+        assert!(len > 0 && len <= 32);
+
+        for _ in 0..len {
+            self.write_bit((bits & 1) as u8)?;
+            bits >>= 1;
+        }
+        Ok(())
+    }
+}
+
+pub type MemBitWriter = BitWriter<Vec<u8>>;
+
+impl MemBitWriter {
+    /// Returns the bytes written so far, including any partial last byte.
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        if let Some(byte) = self.byte.take() {
+            self.wd.push(byte);
+        }
+        std::mem::take(&mut self.wd)
+    }
+}
+
+impl<W: Read + Write + Seek> BitWriter<W> {
+    /// Moves the write position to bit `pos`, counted from where the writer
+    /// started. When `pos` is not on a byte boundary the bits of that byte
+    /// below it are read back and kept.
+    pub fn seek_bit(&mut self, pos: u64) -> Result<()> {
+        // Only whole bytes reach `wd`, so it sits at the current byte.
+        let start = self.wd.stream_position()? - self.bit_position / 8;
+        self.flush_byte()?;
+        let target = start + pos / 8;
+        self.wd.seek(SeekFrom::Start(target))?;
+        self.bit_position = pos;
+
+        if !pos.is_multiple_of(8) {
+            // A byte past the end reads as nothing, so it starts from zero.
+            let mut buf = [0];
+            let existing = match self.wd.read(&mut buf)? {
+                0 => 0,
+                _ => buf[0],
+            };
+            self.wd.seek(SeekFrom::Start(target))?;
+            let below = (pos - pos % 8..pos).fold(0, |mask, bit| mask | 1 << self.order.shift(bit));
+            self.byte = Some(existing & below);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for BitWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.flush_byte();
+    }
+}
+
+pub(crate) struct WordPacker {
+    word_size: u8,
+    words: Vec<u8>,
+    bit_position: u64,
+}
+
+impl WordPacker {
+    pub fn new(word_size: u8) -> Self {
+        Self {
+            word_size,
+            words: Vec::new(),
+            bit_position: 0,
+        }
+    }
+
+    pub fn push(&mut self, value: u64, len: u8) {
+        for i in 0..len {
+            let bit = ((value >> i) & 1) as u8;
+            let offset = (self.bit_position % self.word_size as u64) as u8;
+            if offset == 0 {
+                self.words.push(0);
+            }
+
+            *self.words.last_mut().unwrap() |= bit << offset;
+            self.bit_position += 1;
+        }
+    }
+
+    pub fn into_words(self) -> Vec<u8> {
+        self.words
+    }
+}
+
+pub(crate) struct WordUnpacker<F: FnMut() -> Result<u8>> {
+    word_size: u8,
+    next_word: F,
+    word: u8,
+    bit_position: u64,
+}
+
+impl<F: FnMut() -> Result<u8>> WordUnpacker<F> {
+    pub fn new(word_size: u8, next_word: F) -> Self {
+        Self {
+            word_size,
+            next_word,
+            word: 0,
+            bit_position: 0,
+        }
+    }
+
+    pub fn take(&mut self, len: u8) -> Result<u64> {
+        let mut value: u64 = 0;
+        for i in 0..len {
+            let offset = (self.bit_position % self.word_size as u64) as u8;
+            if offset == 0 {
+                self.word = (self.next_word)()?;
+            }
+
+            value |= (((self.word >> offset) & 1) as u64) << i;
+            self.bit_position += 1;
+        }
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::StegError;
+    use crate::test_util::temp_path;
+    use std::{fs, io};
+
+    struct CountingReader<R> {
+        inner: R,
+        reads: u64,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    impl<R: Seek> Seek for CountingReader<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn reader_reads_each_byte_once() {
+        let data: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let counting = CountingReader {
+            inner: Cursor::new(&data[..]),
+            reads: 0,
+        };
+
+        let mut reader = BitReader::new(counting).unwrap();
+        for &byte in &data {
+            let low = reader.read_bits(3).unwrap();
+            let high = reader.read_bits(5).unwrap();
+            assert_eq!(low | high << 3, byte);
+        }
+
+        assert_eq!(reader.rd.reads, data.len() as u64);
+    }
+
+    #[test]
+    fn mem_reader_reads_slice_bits() {
+        let data = [0b1100_1010, 0x81];
+        let mut reader = MemBitReader::from_slice(&data);
+        assert_eq!(reader.size, 2);
+
+        let bits: Vec<u8> = (0..16).map(|_| reader.read_bit().unwrap()).collect();
+        assert_eq!(bits, [0, 1, 0, 1, 0, 0, 1, 1, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(matches!(reader.read_bit(), Err(StegError::Truncated)));
+    }
+
+    #[test]
+    fn mem_writer_collects_bytes() {
+        let mut writer = MemBitWriter::new(Vec::new());
+        writer.write_bits(0b1010, 4).unwrap();
+        writer.write_bits(0b0110, 4).unwrap();
+        writer.write_bits(0b11, 2).unwrap();
+        assert_eq!(writer.into_bytes(), [0b0110_1010, 0b11]);
+    }
+
+    #[test]
+    fn rewind_rereads_same_bits() {
+        let path = temp_path("rewind.in");
+        fs::write(&path, [0b1011_0110, 0xff]).unwrap();
+
+        let mut reader = FileBitReader::open(&path).unwrap();
+        let first = reader.read_bits(5).unwrap();
+        reader.rewind().unwrap();
+        assert_eq!(reader.read_bits(5).unwrap(), first);
+        assert_eq!(first, 0b1_0110);
+
+        reader.seek_bit(6).unwrap();
+        assert_eq!(reader.read_bits(4).unwrap(), 0b1110);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn round_trips_through_memory() {
+        let mut buf = Vec::new();
+        {
+            let mut writer = BitWriter::new(&mut buf);
+            writer.write_bits(0b101, 3).unwrap();
+            writer.write_bits_u32(0xdead_beef, 32).unwrap();
+        }
+        assert_eq!(buf.len(), 5);
+
+        let mut reader = BitReader::new(Cursor::new(buf)).unwrap();
+        assert_eq!(reader.size, 5);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        assert_eq!(reader.read_bits_u32(32).unwrap(), 0xdead_beef);
+    }
+
+    struct FailingSink;
+
+    impl Write for FailingSink {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn finish_reports_write_errors() {
+        let mut writer = BitWriter::new(FailingSink);
+        writer.write_bits(0b101, 3).unwrap();
+
+        let err = writer.finish().unwrap_err();
+        assert!(matches!(err, StegError::Io(ref e) if e.to_string() == "disk full"));
+    }
+
+    #[test]
+    fn writes_after_mid_byte_seek_land_at_offset() {
+        let mut buf = Vec::new();
+        {
+            let mut writer = BitWriter::new(Cursor::new(&mut buf));
+            writer.write_bits(0xff, 8).unwrap();
+            writer.seek_bit(13).unwrap();
+            writer.write_bits(0b101, 3).unwrap();
+            writer.finish().unwrap();
+        }
+        assert_eq!(buf, [0xff, 0b1010_0000]);
+    }
+
+    #[test]
+    fn mid_byte_seek_keeps_bits_below_target() {
+        for (order, expected) in [
+            (BitOrder::LsbFirst, 0b0001_1111),
+            (BitOrder::MsbFirst, 0b1111_1000),
+        ] {
+            let mut buf = vec![0xff, 0xff];
+            let mut writer = BitWriter::with_order(Cursor::new(&mut buf), order);
+            writer.seek_bit(13).unwrap();
+            writer.write_bits(0, 3).unwrap();
+            writer.finish().unwrap();
+            assert_eq!(buf, [0xff, expected]);
+        }
+    }
+
+    #[test]
+    fn seek_is_relative_to_writer_start() {
+        let mut buf = vec![0xAA; 4];
+        let mut cursor = Cursor::new(&mut buf);
+        cursor.seek(SeekFrom::Start(2)).unwrap();
+
+        let mut writer = BitWriter::new(cursor);
+        writer.write_bits(0x11, 8).unwrap();
+        writer.seek_bit(12).unwrap();
+        writer.write_bits(0b1111, 4).unwrap();
+        writer.seek_bit(0).unwrap();
+        writer.write_bits(0x22, 8).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(buf, [0xAA, 0xAA, 0x22, 0xFA]);
+    }
+
+    #[test]
+    fn msb_first_round_trips() {
+        let path = temp_path("msb_first.bin");
+        let mut writer = FileBitWriter::open_with_order(&path, BitOrder::MsbFirst).unwrap();
+        writer.write_bit(1).unwrap();
+        writer.write_bits(0b011_0100, 7).unwrap();
+        writer.write_bits(0b11, 2).unwrap();
+        drop(writer);
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes, [0b1001_0110, 0b1100_0000]);
+
+        let mut reader = FileBitReader::open_with_order(&path, BitOrder::MsbFirst).unwrap();
+        assert_eq!(reader.read_bit().unwrap(), 1);
+        assert_eq!(reader.read_bits(7).unwrap(), 0b011_0100);
+        assert_eq!(reader.read_bits(2).unwrap(), 0b11);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wide_bit_values_round_trip() {
+        let path = temp_path("wide_bits.bin");
+        let mut writer = FileBitWriter::open(&path).unwrap();
+        writer.write_bits_u32(0x1ABC, 13).unwrap();
+        writer.write_bits_u32(0xC0FFEE, 24).unwrap();
+        writer.write_bits(0b101, 3).unwrap();
+        drop(writer);
+
+        let mut reader = FileBitReader::open(&path).unwrap();
+        assert_eq!(reader.size, 5);
+        assert_eq!(reader.read_bits_u32(13).unwrap(), 0x1ABC);
+        assert_eq!(reader.read_bits_u32(24).unwrap(), 0xC0FFEE);
+        assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1867 @@
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LE};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::error::{Result, StegError};
+use crate::image::{check_same_size, Image, Pixel};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BmpHeader {
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u16,
+    pub compression: u32,
+    pub h_ppm: i32,
+    pub v_ppm: i32,
+    pub num_colors: u32,
+    pub used_colors: u32,
+    pub is_top_down: bool,
+}
+
+/// Channel masks of a 16 or 32bpp BI_BITFIELDS pixel; alpha is 0 when
+/// the file gives only three masks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ColorMasks {
+    r: u32,
+    g: u32,
+    b: u32,
+    a: u32,
+}
+
+impl ColorMasks {
+    const RGB555: Self = Self {
+        r: 0x7C00,
+        g: 0x03E0,
+        b: 0x001F,
+        a: 0,
+    };
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            r: LE::read_u32(&bytes[0..]),
+            g: LE::read_u32(&bytes[4..]),
+            b: LE::read_u32(&bytes[8..]),
+            a: bytes.get(12..16).map_or(0, LE::read_u32),
+        }
+    }
+
+    // Scales the masked field up to 8 bits so its maximum maps to 255.
+    fn channel(value: u32, mask: u32) -> u8 {
+        if mask == 0 {
+            return 0;
+        }
+        let max = (mask >> mask.trailing_zeros()) as u64;
+        let field = ((value & mask) >> mask.trailing_zeros()) as u64;
+        ((field * 255 + max / 2) / max) as u8
+    }
+
+    fn expand(&self, value: u32) -> Pixel {
+        Pixel {
+            r: Self::channel(value, self.r),
+            g: Self::channel(value, self.g),
+            b: Self::channel(value, self.b),
+            a: if self.a == 0 {
+                0xFF
+            } else {
+                Self::channel(value, self.a)
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct BMP {
+    magic: u16,
+    offset: u32,
+    hdr_size: u32,
+    width: u32,
+    height: u32,
+    num_planes: u16,
+    bpp: u16,
+    compression: u32,
+    h_ppm: i32,
+    v_ppm: i32,
+    num_colors: u32,
+    used_colors: u32,
+    is_top_down: bool,
+
+    ext_header: Vec<u8>,
+    gap: Vec<u8>,
+    palette: Vec<Pixel>,
+    masks: Option<ColorMasks>,
+    pixels: Vec<Pixel>,
+}
+
+impl BMP {
+    pub(crate) const MAGIC: u16 = 0x4D42;
+    const FILE_HEADER_SIZE: u32 = 14;
+    pub(crate) const INFO_HEADER_SIZE: u32 = 40;
+    pub(crate) const CORE_HEADER_SIZE: u32 = 12;
+    const DEFAULT_PPM: i32 = 2835;
+    pub(crate) const BI_RGB: u32 = 0;
+    const BI_RLE8: u32 = 1;
+    const BI_BITFIELDS: u32 = 3;
+
+    const INCHES_PER_METER: f32 = 39.3701;
+
+    pub const MAX_PIXELS: u64 = 1 << 28;
+    pub const DIFF_GAIN: u8 = 32;
+
+    // Rows are padded to whole 32-bit words; counting bits rather than
+    // bytes keeps sub-byte depths, which pack several pixels per byte, right.
+    pub(crate) fn row_size(bpp: u16, width: u32) -> u32 {
+        ((bpp as u64 * width as u64).div_ceil(32) * 4) as u32
+    }
+
+    pub fn new(width: u32, height: u32) -> Self {
+        Self::filled(
+            width,
+            height,
+            Pixel {
+                r: 0xFF,
+                g: 0xFF,
+                b: 0xFF,
+                a: 0xFF,
+            },
+        )
+    }
+
+    pub fn filled(width: u32, height: u32, color: Pixel) -> Self {
+        let offset = Self::FILE_HEADER_SIZE + Self::INFO_HEADER_SIZE;
+        let pixels = vec![color; width as usize * height as usize];
+
+        BMP {
+            magic: Self::MAGIC,
+            offset,
+            hdr_size: Self::INFO_HEADER_SIZE,
+            width,
+            height,
+            num_planes: 1,
+            bpp: 24,
+            compression: Self::BI_RGB,
+            h_ppm: Self::DEFAULT_PPM,
+            v_ppm: Self::DEFAULT_PPM,
+            num_colors: 0,
+            used_colors: 0,
+            is_top_down: false,
+            ext_header: Vec::new(),
+            gap: Vec::new(),
+            palette: Vec::new(),
+            masks: None,
+            pixels,
+        }
+    }
+
+    pub fn header(&self) -> BmpHeader {
+        BmpHeader {
+            width: self.width,
+            height: self.height,
+            bpp: self.bpp,
+            compression: self.compression,
+            h_ppm: self.h_ppm,
+            v_ppm: self.v_ppm,
+            num_colors: self.num_colors,
+            used_colors: self.used_colors,
+            is_top_down: self.is_top_down,
+        }
+    }
+
+    pub fn dpi(&self) -> (f32, f32) {
+        (
+            self.h_ppm as f32 / Self::INCHES_PER_METER,
+            self.v_ppm as f32 / Self::INCHES_PER_METER,
+        )
+    }
+
+    pub fn set_dpi(&mut self, x: f32, y: f32) {
+        self.h_ppm = (x * Self::INCHES_PER_METER).round() as i32;
+        self.v_ppm = (y * Self::INCHES_PER_METER).round() as i32;
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Self::read_from(BufReader::new(file))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::read_from(Cursor::new(data))
+    }
+
+    pub fn read_from<R: Read + Seek>(rd: R) -> Result<Self> {
+        Self::read_from_with_limit(rd, Self::MAX_PIXELS)
+    }
+
+    /// Only the OS/2 core header and the 40-byte info header with its
+    /// later extensions are understood.
+    pub(crate) fn check_header_size(hdr_size: u32) -> Result<()> {
+        if hdr_size != Self::CORE_HEADER_SIZE && hdr_size < Self::INFO_HEADER_SIZE {
+            return Err(StegError::UnsupportedHeaderSize(hdr_size));
+        }
+        Ok(())
+    }
+
+    pub fn read_from_with_limit<R: Read + Seek>(mut rd: R, max_pixels: u64) -> Result<Self> {
+        let file_size = rd.seek(SeekFrom::End(0))?;
+        rd.seek(SeekFrom::Start(0))?;
+
+        let magic = rd.read_u16::<LE>()?;
+        if magic != Self::MAGIC {
+            return Err(StegError::BadMagic(magic));
+        }
+        let _ = rd.read_u32::<LE>()?;
+        let _ = rd.read_u32::<LE>()?;
+        let offset = rd.read_u32::<LE>()?;
+        let hdr_size = rd.read_u32::<LE>()?;
+        Self::check_header_size(hdr_size)?;
+        // OS/2 BITMAPCOREHEADER: 16-bit dimensions, no fields past bpp.
+        let is_core = hdr_size == Self::CORE_HEADER_SIZE;
+        let (width, height) = if is_core {
+            (rd.read_u16::<LE>()? as u32, rd.read_u16::<LE>()? as i32)
+        } else {
+            (rd.read_u32::<LE>()?, rd.read_i32::<LE>()?)
+        };
+        let is_top_down = height < 0;
+        let height = height.unsigned_abs();
+        let num_planes = rd.read_u16::<LE>()?;
+        if num_planes != 1 {
+            return Err(StegError::InvalidPlanes(num_planes));
+        }
+        let bpp = rd.read_u16::<LE>()?;
+        if ![8, 16, 24, 32].contains(&bpp) {
+            return Err(StegError::UnsupportedBpp(bpp));
+        }
+        let compression = if is_core {
+            Self::BI_RGB
+        } else {
+            rd.read_u32::<LE>()?
+        };
+        let is_rle8 = compression == Self::BI_RLE8 && bpp == 8;
+        let is_bitfields = compression == Self::BI_BITFIELDS && (bpp == 16 || bpp == 32);
+        if compression != Self::BI_RGB && !is_rle8 && !is_bitfields {
+            return Err(StegError::Compressed(compression));
+        }
+        // image_size is recomputed on write, so the stored value is skipped.
+        let (h_ppm, v_ppm, num_colors, used_colors) = if is_core {
+            (0, 0, 0, 0)
+        } else {
+            let _ = rd.read_u32::<LE>()?;
+            (
+                rd.read_i32::<LE>()?,
+                rd.read_i32::<LE>()?,
+                rd.read_u32::<LE>()?,
+                rd.read_u32::<LE>()?,
+            )
+        };
+
+        let (w, h) = (width as u64, height as u64);
+        if w.max(h).max(w * h) > max_pixels {
+            return Err(StegError::TooLarge {
+                width,
+                height,
+                limit: max_pixels,
+            });
+        }
+
+        let row_size = Self::row_size(bpp, width);
+        if !is_rle8 && offset as u64 + h * row_size as u64 > file_size {
+            // Zero-width rows take no bytes, so only the offset can be short.
+            let row_size = (row_size as u64).max(1);
+            let available = file_size.saturating_sub(offset as u64);
+            let partial = (available % row_size) / (bpp as u64 / 8);
+            return Err(StegError::TruncatedPixels {
+                expected: w * h,
+                got: available / row_size * w + partial.min(w),
+            });
+        }
+
+        // V4/V5 headers extend the 40-byte info header; keep the extra fields
+        // and anything between the palette and the pixel data verbatim.
+        let mut ext_header = vec![0u8; hdr_size.saturating_sub(Self::INFO_HEADER_SIZE) as usize];
+        rd.read_exact(&mut ext_header)?;
+
+        let mut palette: Vec<Pixel> = Vec::new();
+        if bpp <= 8 {
+            let entries = if num_colors == 0 {
+                1 << bpp
+            } else {
+                num_colors
+            };
+
+            rd.seek(SeekFrom::Start(14 + hdr_size as u64))?;
+            for _ in 0..entries {
+                let b = rd.read_u8()?;
+                let g = rd.read_u8()?;
+                let r = rd.read_u8()?;
+                let a = if is_core { 0 } else { rd.read_u8()? };
+                palette.push(Pixel { r, g, b, a });
+            }
+        }
+
+        let palette_end = rd.stream_position()?;
+        let mut gap = vec![0u8; (offset as u64).saturating_sub(palette_end) as usize];
+        rd.read_exact(&mut gap)?;
+
+        // BI_BITFIELDS masks follow a 40-byte info header or sit inside a
+        // larger one, which also holds an alpha mask.
+        let masks = if is_bitfields {
+            let fields = if ext_header.len() >= 16 {
+                &ext_header[..16]
+            } else if ext_header.len() >= 12 {
+                &ext_header[..12]
+            } else {
+                gap.get(..12).ok_or_else(|| {
+                    StegError::Corrupted("BI_BITFIELDS BMP is missing its masks".into())
+                })?
+            };
+            Some(ColorMasks::from_bytes(fields))
+        } else {
+            None
+        };
+
+        let rle = if is_rle8 {
+            rd.seek(SeekFrom::Start(offset as u64))?;
+            Some(Self::decode_rle8(&mut rd, width, height, row_size)?)
+        } else {
+            None
+        };
+
+        let mut row_buf = vec![0u8; row_size as usize];
+
+        let mut pixels: Vec<Pixel> = Vec::new();
+        for y in 0..height {
+            let row = if is_top_down { y } else { height - 1 - y };
+            if let Some(rle) = &rle {
+                let start = row as usize * row_size as usize;
+                row_buf.copy_from_slice(&rle[start..start + row_size as usize]);
+            } else {
+                rd.seek(SeekFrom::Start(
+                    offset as u64 + row as u64 * row_size as u64,
+                ))?;
+                rd.read_exact(&mut row_buf)?;
+            }
+
+            Self::decode_row(bpp, &palette, masks, &row_buf, width, &mut pixels)?;
+        }
+
+        // Pixels are held decoded, so the image is written back uncompressed,
+        // with 32bpp in the standard BGRA order and 16bpp images as 24bpp so
+        // embedded bits aren't packed away.
+        let compression = if is_rle8 || is_bitfields {
+            Self::BI_RGB
+        } else {
+            compression
+        };
+        let bpp = if bpp == 16 { 24 } else { bpp };
+
+        // OS/2 images are written back with a BITMAPINFOHEADER and 4-byte
+        // palette entries, which moves the pixel data.
+        let (hdr_size, gap) = if is_core {
+            (Self::INFO_HEADER_SIZE, Vec::new())
+        } else {
+            (hdr_size, gap)
+        };
+        let offset =
+            Self::FILE_HEADER_SIZE + hdr_size + 4 * palette.len() as u32 + gap.len() as u32;
+
+        Ok(BMP {
+            magic,
+            offset,
+            hdr_size,
+            width,
+            height,
+            num_planes,
+            num_colors,
+            bpp,
+            compression,
+            h_ppm,
+            v_ppm,
+            used_colors,
+            is_top_down,
+            ext_header,
+            gap,
+            palette,
+            masks,
+            pixels,
+        })
+    }
+
+    pub(crate) fn decode_row(
+        bpp: u16,
+        palette: &[Pixel],
+        masks: Option<ColorMasks>,
+        row: &[u8],
+        width: u32,
+        out: &mut Vec<Pixel>,
+    ) -> Result<()> {
+        let bytes_per_pixel = (bpp / 8) as usize;
+        let row_bytes = &row[..width as usize * bytes_per_pixel];
+        for chunk in row_bytes.chunks_exact(bytes_per_pixel) {
+            out.push(Self::decode_pixel(bpp, palette, masks, chunk)?);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn decode_pixel(
+        bpp: u16,
+        palette: &[Pixel],
+        masks: Option<ColorMasks>,
+        chunk: &[u8],
+    ) -> Result<Pixel> {
+        if bpp == 8 {
+            let index = chunk[0];
+            let entry = palette.get(index as usize).ok_or_else(|| {
+                StegError::Corrupted(format!("palette index {} out of range", index))
+            })?;
+            return Ok(Pixel {
+                r: entry.r,
+                g: entry.g,
+                b: entry.b,
+                a: 0xFF,
+            });
+        }
+        if bpp == 16 {
+            let masks = masks.unwrap_or(ColorMasks::RGB555);
+            return Ok(masks.expand(LE::read_u16(chunk) as u32));
+        }
+        if let Some(masks) = masks.filter(|_| bpp == 32) {
+            return Ok(masks.expand(LE::read_u32(chunk)));
+        }
+
+        let (b, g, r) = (chunk[0], chunk[1], chunk[2]);
+        let a = if bpp == 32 { chunk[3] } else { 0xFF };
+        Ok(Pixel { r, g, b, a })
+    }
+
+    /// Expands an RLE8 stream into an uncompressed pixel array laid out like
+    /// the file's rows. Pixels skipped by deltas or early line ends keep
+    /// palette index 0.
+    fn decode_rle8<R: Read>(rd: &mut R, width: u32, height: u32, row_size: u32) -> Result<Vec<u8>> {
+        let mut out = vec![0u8; row_size as usize * height as usize];
+        let (mut x, mut row) = (0u32, 0u32);
+        let mut put = |x: u32, row: u32, index: u8| {
+            if x >= width || row >= height {
+                return Err(StegError::Corrupted(
+                    "RLE8 data exceeds image bounds".into(),
+                ));
+            }
+            out[row as usize * row_size as usize + x as usize] = index;
+            Ok(())
+        };
+
+        while row < height {
+            let count = rd.read_u8()?;
+            let value = rd.read_u8()?;
+            match (count, value) {
+                (0, 0) => {
+                    x = 0;
+                    row += 1;
+                }
+                (0, 1) => break,
+                (0, 2) => {
+                    x += rd.read_u8()? as u32;
+                    row += rd.read_u8()? as u32;
+                }
+                (0, len) => {
+                    for _ in 0..len {
+                        put(x, row, rd.read_u8()?)?;
+                        x += 1;
+                    }
+                    if len % 2 == 1 {
+                        rd.read_u8()?;
+                    }
+                }
+                (len, index) => {
+                    for _ in 0..len {
+                        put(x, row, index)?;
+                        x += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        // Dropping a BufWriter flushes but discards errors, and a successful
+        // flush still leaves data in the OS cache, so both are done here.
+        let mut wd = BufWriter::new(file);
+        self.write_to(&mut wd)?;
+        wd.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Writes to a temporary file next to `path` and renames it over
+    /// `path`, so a crash leaves either the old file or the new one.
+    pub fn write_atomic(&self, path: &Path) -> Result<()> {
+        let temp = self.write_temp(path)?;
+        fs::rename(&temp, path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })?;
+        Ok(())
+    }
+
+    // Same directory, since a rename can't cross filesystems.
+    fn write_temp(&self, path: &Path) -> Result<PathBuf> {
+        let name = path.file_name().ok_or_else(|| {
+            StegError::InvalidArgument(format!("{} is not a file path", path.display()))
+        })?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(name);
+        temp_name.push(format!(".{}.tmp", std::process::id()));
+        let temp = path.with_file_name(temp_name);
+
+        self.write(&temp).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })?;
+        Ok(temp)
+    }
+
+    /// Red, green, blue and alpha masks read from a BI_BITFIELDS file, which
+    /// the pixels were decoded with; `None` for other files.
+    pub fn color_masks(&self) -> Option<[u32; 4]> {
+        self.masks.map(|m| [m.r, m.g, m.b, m.a])
+    }
+
+    /// Palette index of every pixel in row-major order, or `None` unless
+    /// this is an 8bpp image whose pixels all match a palette entry exactly.
+    fn palette_indices(&self) -> Option<Vec<u8>> {
+        if self.bpp != 8 {
+            return None;
+        }
+
+        // The first entry of each colour wins, so duplicate entries still
+        // give back the indices an unmodified image was read with.
+        let mut lookup = HashMap::new();
+        for (index, entry) in self.palette.iter().take(256).enumerate().rev() {
+            lookup.insert((entry.r, entry.g, entry.b), index as u8);
+        }
+        self.pixels
+            .iter()
+            .map(|pixel| lookup.get(&(pixel.r, pixel.g, pixel.b)).copied())
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.write_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        self.to_bytes()
+    }
+
+    pub fn write_to<W: Write>(&self, mut wd: W) -> Result<()> {
+        // Pixel data is only ever written uncompressed.
+        if self.compression != Self::BI_RGB {
+            return Err(StegError::Compressed(self.compression));
+        }
+
+        // An 8bpp image keeps its palette while every pixel still matches an
+        // entry exactly; once embedding moves one off it, the image is
+        // promoted to 24bpp so the changed bits survive.
+        let indices = self.palette_indices();
+        let (bpp, palette, num_colors, used_colors) = match indices {
+            Some(_) => (8, &self.palette[..], self.num_colors, self.used_colors),
+            None if self.bpp == 8 => (24, &[][..], 0, 0),
+            None => (self.bpp, &[][..], self.num_colors, self.used_colors),
+        };
+        let offset = self.offset - 4 * (self.palette.len() - palette.len()) as u32;
+
+        // `new` and `filled` take any dimensions, so sizes that don't fit the
+        // header fields are caught here.
+        let too_large = || StegError::TooLarge {
+            width: self.width,
+            height: self.height,
+            limit: Self::MAX_PIXELS,
+        };
+        // image_size may legally be 0 for BI_RGB, but some readers reject
+        // that, so the real size is always written.
+        let image_size = self
+            .height
+            .checked_mul(Self::row_size(bpp, self.width))
+            .ok_or_else(too_large)?;
+        let size = offset.checked_add(image_size).ok_or_else(too_large)?;
+        let height = i32::try_from(self.height).map_err(|_| too_large())?;
+
+        wd.write_u16::<LE>(self.magic)?;
+        wd.write_u32::<LE>(size)?;
+        wd.write_u32::<LE>(0)?;
+        wd.write_u32::<LE>(offset)?;
+        wd.write_u32::<LE>(self.hdr_size)?;
+        wd.write_u32::<LE>(self.width)?;
+        wd.write_i32::<LE>(if self.is_top_down { -height } else { height })?;
+        wd.write_u16::<LE>(self.num_planes)?;
+        wd.write_u16::<LE>(bpp)?;
+        wd.write_u32::<LE>(self.compression)?;
+        wd.write_u32::<LE>(image_size)?;
+        wd.write_i32::<LE>(self.h_ppm)?;
+        wd.write_i32::<LE>(self.v_ppm)?;
+        wd.write_u32::<LE>(num_colors)?;
+        wd.write_u32::<LE>(used_colors)?;
+        wd.write_all(&self.ext_header)?;
+
+        for entry in palette {
+            wd.write_u8(entry.b)?;
+            wd.write_u8(entry.g)?;
+            wd.write_u8(entry.r)?;
+            wd.write_u8(entry.a)?;
+        }
+        wd.write_all(&self.gap)?;
+
+        let pad = {
+            let row_bytes = (bpp as u32 / 8) * self.width;
+            Self::row_size(bpp, self.width).saturating_sub(row_bytes)
+        };
+        debug_assert!(pad < 4);
+
+        for row in 0..self.height {
+            let y = if self.is_top_down {
+                row
+            } else {
+                self.height - 1 - row
+            };
+            for x in 0..self.width {
+                if let Some(indices) = &indices {
+                    wd.write_u8(indices[y as usize * self.width as usize + x as usize])?;
+                    continue;
+                }
+
+                let pixel = self.pixel(x, y);
+                wd.write_u8(pixel.b)?;
+                wd.write_u8(pixel.g)?;
+                wd.write_u8(pixel.r)?;
+                if bpp == 32 {
+                    wd.write_u8(pixel.a)?;
+                }
+            }
+
+            for _ in 0..pad {
+                wd.write_u8(0)?;
+            }
+        }
+
+        wd.flush()?;
+        Ok(())
+    }
+
+    /// Loads a PNG of any colour type into a 24bpp image, or 32bpp when the
+    /// PNG carries alpha.
+    #[cfg(feature = "png")]
+    pub fn read_png(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let png_err = |e: png::DecodingError| StegError::Png(e.to_string());
+
+        let mut decoder = png::Decoder::new(BufReader::new(file));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(png_err)?;
+        let size = reader
+            .output_buffer_size()
+            .ok_or_else(|| StegError::Png("image too large".into()))?;
+        let mut buf = vec![0; size];
+        let info = reader.next_frame(&mut buf).map_err(png_err)?;
+
+        let mut bmp = BMP::new(info.width, info.height);
+        let channels = info.color_type.samples();
+        let rows = buf.chunks(info.line_size).take(info.height as usize);
+        for (row, pixels) in rows.zip(bmp.pixels.chunks_mut(info.width as usize)) {
+            for (pixel, px) in pixels.iter_mut().zip(row.chunks_exact(channels)) {
+                *pixel = match info.color_type {
+                    png::ColorType::Grayscale => Pixel {
+                        r: px[0],
+                        g: px[0],
+                        b: px[0],
+                        a: 0xFF,
+                    },
+                    png::ColorType::GrayscaleAlpha => Pixel {
+                        r: px[0],
+                        g: px[0],
+                        b: px[0],
+                        a: px[1],
+                    },
+                    png::ColorType::Rgb => Pixel {
+                        r: px[0],
+                        g: px[1],
+                        b: px[2],
+                        a: 0xFF,
+                    },
+                    _ => Pixel {
+                        r: px[0],
+                        g: px[1],
+                        b: px[2],
+                        a: px[3],
+                    },
+                };
+            }
+        }
+
+        if matches!(
+            info.color_type,
+            png::ColorType::Rgba | png::ColorType::GrayscaleAlpha
+        ) {
+            bmp.bpp = 32;
+        }
+        Ok(bmp)
+    }
+
+    /// Saves the pixels as an 8-bit RGB PNG, or RGBA for 32bpp images. PNG is
+    /// lossless, so any embedded payload survives the conversion.
+    #[cfg(feature = "png")]
+    pub fn write_png(&self, path: &Path) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        let has_alpha = self.bpp == 32;
+        encoder.set_color(if has_alpha {
+            png::ColorType::Rgba
+        } else {
+            png::ColorType::Rgb
+        });
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut data = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            data.extend_from_slice(&[pixel.r, pixel.g, pixel.b]);
+            if has_alpha {
+                data.push(pixel.a);
+            }
+        }
+
+        let png_err = |e: png::EncodingError| StegError::Png(e.to_string());
+        let mut writer = encoder.write_header().map_err(png_err)?;
+        writer.write_image_data(&data).map_err(png_err)?;
+        writer.finish().map_err(png_err)
+    }
+
+    /// Replaces every pixel's colour with its Rec. 601 luma.
+    pub fn to_grayscale(&mut self) {
+        for pixel in &mut self.pixels {
+            let luma = pixel.luminance();
+            (pixel.r, pixel.g, pixel.b) = (luma, luma, luma);
+        }
+    }
+
+    /// Every pixel in row-major order, top row first, whatever the row
+    /// order in the file.
+    pub fn pixels_slice(&self) -> &[Pixel] {
+        &self.pixels
+    }
+
+    pub fn pixels_slice_mut(&mut self) -> &mut [Pixel] {
+        &mut self.pixels
+    }
+
+    pub fn flip_vertical(&mut self) {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        for row in 0..height / 2 {
+            let (top, bottom) = self.pixels.split_at_mut((height - 1 - row) * width);
+            top[row * width..(row + 1) * width].swap_with_slice(&mut bottom[..width]);
+        }
+    }
+
+    pub fn flip_horizontal(&mut self) {
+        if self.width == 0 {
+            return;
+        }
+        for row in self.pixels.chunks_exact_mut(self.width as usize) {
+            row.reverse();
+        }
+    }
+
+    /// Copies the `w`x`h` region with top-left corner at (`x`, `y`) into a new
+    /// image with the same format as this one.
+    pub fn crop(&self, x: u32, y: u32, w: u32, h: u32) -> Result<BMP> {
+        let fits =
+            |start: u32, len: u32, max: u32| start.checked_add(len).is_some_and(|end| end <= max);
+        if !fits(x, w, self.width) || !fits(y, h, self.height) {
+            return Err(StegError::InvalidArgument(format!(
+                "crop region {}x{} at ({}, {}) exceeds image {}x{}",
+                w, h, x, y, self.width, self.height
+            )));
+        }
+
+        let mut pixels = Vec::with_capacity(w as usize * h as usize);
+        for row in y..y + h {
+            for col in x..x + w {
+                pixels.push(self.pixel(col, row));
+            }
+        }
+
+        Ok(BMP {
+            magic: self.magic,
+            offset: self.offset,
+            hdr_size: self.hdr_size,
+            width: w,
+            height: h,
+            num_planes: self.num_planes,
+            bpp: self.bpp,
+            compression: Self::BI_RGB,
+            h_ppm: self.h_ppm,
+            v_ppm: self.v_ppm,
+            num_colors: self.num_colors,
+            used_colors: self.used_colors,
+            is_top_down: self.is_top_down,
+            ext_header: self.ext_header.clone(),
+            gap: self.gap.clone(),
+            palette: self.palette.clone(),
+            masks: self.masks,
+            pixels,
+        })
+    }
+
+    /// Renders the per-channel absolute difference to `other`, amplified so
+    /// that single-bit LSB changes are clearly visible.
+    pub fn diff(&self, other: &BMP) -> Result<BMP> {
+        check_same_size(self, other)?;
+
+        let amplify = |a: u8, b: u8| a.abs_diff(b).saturating_mul(Self::DIFF_GAIN);
+        let mut out = BMP::new(self.width, self.height);
+        for ((pixel, a), b) in out.pixels.iter_mut().zip(&self.pixels).zip(&other.pixels) {
+            pixel.r = amplify(a.r, b.r);
+            pixel.g = amplify(a.g, b.g);
+            pixel.b = amplify(a.b, b.b);
+        }
+        Ok(out)
+    }
+
+    /// Peak signal-to-noise ratio in dB over the red, green and blue
+    /// channels; infinite when the images are identical.
+    pub fn psnr(&self, other: &BMP) -> Result<f64> {
+        check_same_size(self, other)?;
+
+        let squared: u64 = self
+            .pixels
+            .iter()
+            .zip(&other.pixels)
+            .map(|(a, b)| {
+                [(a.r, b.r), (a.g, b.g), (a.b, b.b)]
+                    .iter()
+                    .map(|&(x, y)| (x.abs_diff(y) as u64).pow(2))
+                    .sum::<u64>()
+            })
+            .sum();
+        if squared == 0 {
+            return Ok(f64::INFINITY);
+        }
+
+        let mse = squared as f64 / (self.pixels.len() * 3) as f64;
+        Ok(10.0 * (255.0 * 255.0 / mse).log10())
+    }
+}
+
+impl Image for BMP {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> Pixel {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    fn has_alpha(&self) -> bool {
+        self.bpp == 32
+    }
+
+    fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
+        &mut self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    fn contiguous_pixels_mut(&mut self) -> Option<&mut [Pixel]> {
+        Some(&mut self.pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bits::FileBitReader;
+    use crate::stream::ImageDataStream;
+    use crate::test_util::temp_path;
+    use std::fs;
+
+    fn header_bytes(width: u32, height: u32, bpp: u16) -> Vec<u8> {
+        let mut hdr = Vec::new();
+        hdr.write_u16::<LE>(BMP::MAGIC).unwrap();
+        hdr.write_u32::<LE>(0).unwrap();
+        hdr.write_u32::<LE>(0).unwrap();
+        hdr.write_u32::<LE>(54).unwrap();
+        hdr.write_u32::<LE>(40).unwrap();
+        hdr.write_u32::<LE>(width).unwrap();
+        hdr.write_u32::<LE>(height).unwrap();
+        hdr.write_u16::<LE>(1).unwrap();
+        hdr.write_u16::<LE>(bpp).unwrap();
+        hdr.write_u32::<LE>(0).unwrap();
+        let rows = (height as i32).unsigned_abs();
+        hdr.write_u32::<LE>(BMP::row_size(bpp, width).wrapping_mul(rows))
+            .unwrap();
+        hdr.write_i32::<LE>(2835).unwrap();
+        hdr.write_i32::<LE>(2835).unwrap();
+        hdr.write_u32::<LE>(0).unwrap();
+        hdr.write_u32::<LE>(0).unwrap();
+        hdr
+    }
+
+    fn set_u32(data: &mut [u8], pos: usize, value: u32) {
+        data[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_file_size(data: &mut [u8]) {
+        let len = data.len() as u32;
+        set_u32(data, 2, len);
+    }
+
+    #[test]
+    fn reads_16bpp_555_and_565() {
+        let mut data = header_bytes(2, 1, 16);
+        data.extend_from_slice(&0x7C00u16.to_le_bytes());
+        data.extend_from_slice(&0x0210u16.to_le_bytes());
+        set_file_size(&mut data);
+
+        let bmp = BMP::from_bytes(&data).unwrap();
+        let rgb = |p: Pixel| (p.r, p.g, p.b);
+        assert_eq!(rgb(bmp.pixel(0, 0)), (255, 0, 0));
+        assert_eq!(rgb(bmp.pixel(1, 0)), (0, 132, 132));
+
+        let mut data = header_bytes(2, 1, 16);
+        set_u32(&mut data, 10, 54 + 12);
+        set_u32(&mut data, 30, 3);
+        for mask in [0xF800u32, 0x07E0, 0x001F] {
+            data.extend_from_slice(&mask.to_le_bytes());
+        }
+        data.extend_from_slice(&((16u16 << 11) | (32 << 5) | 8).to_le_bytes());
+        data.extend_from_slice(&0xFFFFu16.to_le_bytes());
+        set_file_size(&mut data);
+
+        let bmp = BMP::from_bytes(&data).unwrap();
+        assert_eq!(bmp.color_masks(), Some([0xF800, 0x07E0, 0x001F, 0]));
+        assert_eq!(rgb(bmp.pixel(0, 0)), (132, 130, 66));
+        assert_eq!(rgb(bmp.pixel(1, 0)), (255, 255, 255));
+
+        // Written back as 24bpp so embedded bits survive.
+        let reread = BMP::from_bytes(&bmp.to_bytes().unwrap()).unwrap();
+        assert_eq!(reread.header().bpp, 24);
+        assert_eq!(reread.pixels, bmp.pixels);
+    }
+
+    #[test]
+    fn reads_32bpp_bitfields_in_any_channel_order() {
+        // A V4-sized header keeps red, green, blue and alpha masks inline.
+        let mut data = header_bytes(2, 1, 32);
+        set_u32(&mut data, 10, 54 + 68);
+        set_u32(&mut data, 14, 40 + 68);
+        set_u32(&mut data, 30, 3);
+        for mask in [0x0000_00FFu32, 0x0000_FF00, 0x00FF_0000, 0xFF00_0000] {
+            data.extend_from_slice(&mask.to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 52]);
+        data.extend_from_slice(&[0x10, 0x20, 0x30, 0x40]);
+        data.extend_from_slice(&[0xFF, 0x00, 0x80, 0x00]);
+        set_file_size(&mut data);
+
+        let bmp = BMP::from_bytes(&data).unwrap();
+        assert_eq!(
+            bmp.color_masks(),
+            Some([0xFF, 0xFF00, 0xFF_0000, 0xFF00_0000])
+        );
+        let rgba = |p: Pixel| (p.r, p.g, p.b, p.a);
+        assert_eq!(rgba(bmp.pixel(0, 0)), (0x10, 0x20, 0x30, 0x40));
+        assert_eq!(rgba(bmp.pixel(1, 0)), (0xFF, 0x00, 0x80, 0x00));
+
+        let reread = BMP::from_bytes(&bmp.to_bytes().unwrap()).unwrap();
+        assert_eq!(reread.color_masks(), None);
+        assert_eq!(reread.pixels, bmp.pixels);
+    }
+
+    #[test]
+    fn reads_os2_core_header() {
+        let mut data = Vec::new();
+        data.write_u16::<LE>(BMP::MAGIC).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(0).unwrap();
+        data.write_u32::<LE>(26).unwrap();
+        data.write_u32::<LE>(12).unwrap();
+        data.write_u16::<LE>(2).unwrap();
+        data.write_u16::<LE>(2).unwrap();
+        data.write_u16::<LE>(1).unwrap();
+        data.write_u16::<LE>(24).unwrap();
+        // Bottom row first, BGR triples padded to 8 bytes.
+        data.extend_from_slice(&[3, 2, 1, 6, 5, 4, 0, 0]);
+        data.extend_from_slice(&[9, 8, 7, 12, 11, 10, 0, 0]);
+        set_file_size(&mut data);
+
+        let bmp = BMP::read_from(Cursor::new(data)).unwrap();
+        assert_eq!((bmp.width(), bmp.height()), (2, 2));
+        let rgb = |x, y| {
+            let p = bmp.pixel(x, y);
+            (p.r, p.g, p.b)
+        };
+        assert_eq!(rgb(0, 0), (7, 8, 9));
+        assert_eq!(rgb(1, 0), (10, 11, 12));
+        assert_eq!(rgb(0, 1), (1, 2, 3));
+        assert_eq!(rgb(1, 1), (4, 5, 6));
+
+        let reread = BMP::read_from(Cursor::new(bmp.to_bytes().unwrap())).unwrap();
+        assert_eq!(reread.header().width, 2);
+        assert_eq!(reread.pixels, bmp.pixels);
+    }
+
+    #[test]
+    fn read_rejects_bad_magic() {
+        let path = temp_path("bad_magic.bmp");
+        fs::write(
+            &path,
+            [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F'],
+        )
+        .unwrap();
+
+        let err = BMP::read(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("bad magic 0xd8ff"), "{}", err);
+    }
+
+    #[test]
+    fn read_rejects_unsupported_bpp() {
+        let path = temp_path("bpp4.bmp");
+        let mut data = header_bytes(2, 2, 4);
+        data.resize(data.len() + 2 * 4, 0);
+        fs::write(&path, data).unwrap();
+
+        let err = BMP::read(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("unsupported bpp 4"), "{}", err);
+    }
+
+    #[test]
+    fn read_rejects_unsupported_header_size() {
+        for size in [16, 39] {
+            let mut data = header_bytes(2, 2, 24);
+            set_u32(&mut data, 14, size);
+            data.extend_from_slice(&[0; 16]);
+
+            let err = BMP::read_from(Cursor::new(data)).unwrap_err();
+            assert!(
+                matches!(err, StegError::UnsupportedHeaderSize(s) if s == size),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn read_rejects_multiple_planes() {
+        let mut data = header_bytes(2, 2, 24);
+        data[26..28].copy_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+
+        let err = BMP::read_from(Cursor::new(data)).unwrap_err();
+        assert_eq!(err.to_string(), "invalid BMP: num_planes must be 1, got 3");
+    }
+
+    #[test]
+    fn read_rejects_compressed_bmp() {
+        let mut data = header_bytes(2, 2, 8);
+        set_u32(&mut data, 30, 2);
+        data.extend_from_slice(&[0; 256 * 4 + 8]);
+
+        let err = BMP::read_from(Cursor::new(data)).unwrap_err();
+        assert_eq!(err.to_string(), "compressed BMP (method 2) not supported");
+    }
+
+    #[test]
+    fn rle8_decodes_runs_and_deltas() {
+        let mut data = header_bytes(4, 3, 8);
+        set_u32(&mut data, 10, 54 + 4 * 4);
+        set_u32(&mut data, 30, 1);
+        set_u32(&mut data, 46, 4);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 10, 0, 0, 20, 0, 0, 30, 0, 0, 0]);
+        data.extend_from_slice(&[
+            1, 1, // run of one index 1
+            0, 3, 2, 3, 1, 0, // absolute run, padded to a word
+            0, 0, // end of line
+            0, 2, 1, 1, // delta: skip one column and one row
+            3, 2, // run of three index 2
+            0, 1, // end of bitmap
+        ]);
+
+        let bmp = BMP::read_from(Cursor::new(data)).unwrap();
+        let palette = [(0, 0, 0), (10, 0, 0), (0, 20, 0), (0, 0, 30)];
+        let expected = [[0, 2, 2, 2], [0, 0, 0, 0], [1, 2, 3, 1]];
+        for (y, row) in expected.iter().enumerate() {
+            for (x, &index) in row.iter().enumerate() {
+                let pixel = bmp.pixel(x as u32, y as u32);
+                assert_eq!(
+                    (pixel.r, pixel.g, pixel.b),
+                    palette[index],
+                    "({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+        assert_eq!(bmp.compression, 0);
+    }
+
+    #[test]
+    fn read_reports_truncated_file() {
+        let path = temp_path("truncated.bmp");
+        fs::write(&path, &header_bytes(4, 4, 24)[..30]).unwrap();
+
+        let err = BMP::read(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(err, StegError::Truncated), "{:?}", err);
+    }
+
+    #[test]
+    fn read_rejects_huge_dimensions() {
+        let mut data = header_bytes(50000, 50000, 24);
+        data.extend_from_slice(&[0; 64]);
+
+        let err = BMP::read_from(Cursor::new(data)).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                StegError::TooLarge {
+                    width: 50000,
+                    height: 50000,
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+
+        let mut data = header_bytes(2, 2, 24);
+        set_u32(&mut data, 10, 4096);
+        data.extend_from_slice(&[0; 16]);
+        let err = BMP::read_from(Cursor::new(data)).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                StegError::TruncatedPixels {
+                    expected: 4,
+                    got: 0
+                }
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn read_reports_truncated_pixel_array() {
+        let mut data = Vec::new();
+        BMP::new(4, 4).write_to(&mut data).unwrap();
+        data.truncate(54 + 4 * 4 * 3 / 2);
+
+        let err = BMP::read_from(Cursor::new(data)).unwrap_err();
+        assert_eq!(err.to_string(), "truncated BMP: expected 16 pixels, got 8");
+
+        let mut data = header_bytes(0, 4, 24);
+        set_u32(&mut data, 10, 4096);
+        set_file_size(&mut data);
+        let err = BMP::read_from(Cursor::new(data)).unwrap_err();
+        assert!(
+            matches!(err, StegError::TruncatedPixels { expected: 0, .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn bpp32_preserves_alpha() {
+        let (width, height) = (8, 6);
+        let carrier_path = temp_path("alpha.bmp");
+        let input_path = temp_path("alpha.in");
+        let stego_path = temp_path("alpha.out.bmp");
+
+        let mut data = header_bytes(width, height, 32);
+        for i in 0..width * height {
+            data.extend_from_slice(&[i as u8, (i * 2) as u8, (i * 3) as u8, (i * 16) as u8]);
+        }
+        fs::write(&carrier_path, &data).unwrap();
+        fs::write(&input_path, b"alpha").unwrap();
+
+        let mut stream = ImageDataStream::new(BMP::read(&carrier_path).unwrap());
+        let mut input = FileBitReader::open(&input_path).unwrap();
+        stream.write_stream(&mut input).unwrap();
+        stream.into_inner().write(&stego_path).unwrap();
+
+        let stego = fs::read(&stego_path).unwrap();
+        fs::remove_file(&carrier_path).unwrap();
+        fs::remove_file(&input_path).unwrap();
+        fs::remove_file(&stego_path).unwrap();
+
+        assert_eq!(stego.len(), data.len());
+        for i in (54..data.len()).step_by(4) {
+            assert_eq!(stego[i + 3], data[i + 3]);
+        }
+        assert_ne!(stego[54..], data[54..]);
+    }
+
+    #[test]
+    fn alpha_mode_hides_payload_in_alpha() {
+        let carrier = || {
+            let mut data = header_bytes(16, 16, 32);
+            for i in 0..16 * 16 {
+                data.extend_from_slice(&[i as u8, (i * 5) as u8, (i * 11) as u8, 0xFF]);
+            }
+            BMP::read_from(Cursor::new(data)).unwrap()
+        };
+
+        let mut stream = ImageDataStream::alpha_only(carrier(), 2).unwrap();
+        stream.embed_bytes(b"translucent").unwrap();
+        let mut buf = Vec::new();
+        stream.into_inner().write_to(&mut buf).unwrap();
+        let stego = BMP::read_from(Cursor::new(buf)).unwrap();
+
+        let expected = carrier();
+        assert!(stego
+            .pixels
+            .iter()
+            .zip(&expected.pixels)
+            .all(|(a, b)| (a.r, a.g, a.b) == (b.r, b.g, b.b)));
+        assert!(stego.pixels.iter().any(|p| p.a != 0xFF));
+
+        let stream = ImageDataStream::new(stego);
+        assert_eq!(stream.extract_bytes().unwrap(), b"translucent");
+    }
+
+    fn gradient8() -> Vec<u8> {
+        let mut data = header_bytes(16, 16, 8);
+        set_u32(&mut data, 10, 54 + 256 * 4);
+        for i in 0..=255u8 {
+            data.extend_from_slice(&[i, i, 255 - i, 0]);
+        }
+        data.extend(0..=255u8);
+        set_file_size(&mut data);
+        data
+    }
+
+    #[test]
+    fn bpp8_round_trip_is_byte_exact() {
+        let path = temp_path("gradient8.bmp");
+        let out_path = temp_path("gradient8.out.bmp");
+        let data = gradient8();
+        fs::write(&path, &data).unwrap();
+
+        let bmp = BMP::read(&path).unwrap();
+        assert_eq!(bmp.palette.len(), 256);
+        let top_left = bmp.pixel(0, 0);
+        assert_eq!((top_left.r, top_left.g, top_left.b), (15, 240, 240));
+
+        bmp.write(&out_path).unwrap();
+        let written = fs::read(&out_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&out_path).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn bpp8_off_palette_pixel_is_written_back_as_24bpp() {
+        let mut bmp = BMP::from_bytes(&gradient8()).unwrap();
+        bmp.pixel_mut(3, 5).r ^= 1;
+
+        let written = bmp.to_bytes().unwrap();
+        assert_eq!(written.len(), 54 + 16 * 16 * 3);
+        let reread = BMP::from_bytes(&written).unwrap();
+        assert_eq!(reread.header().bpp, 24);
+        assert_eq!(reread.header().num_colors, 0);
+        assert_eq!(reread.pixels, bmp.pixels);
+    }
+
+    #[test]
+    fn bpp8_carrier_keeps_embedded_payload() {
+        let carrier_path = temp_path("carrier8.bmp");
+        let stego_path = temp_path("carrier8.out.bmp");
+        fs::write(&carrier_path, gradient8()).unwrap();
+
+        let mut stream = ImageDataStream::new(BMP::read(&carrier_path).unwrap());
+        stream.embed_bytes(b"paletted").unwrap();
+        stream.into_inner().write(&stego_path).unwrap();
+
+        let stream = ImageDataStream::new(BMP::read(&stego_path).unwrap());
+        fs::remove_file(&carrier_path).unwrap();
+        fs::remove_file(&stego_path).unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"paletted");
+    }
+
+    #[test]
+    fn top_down_rows_are_not_flipped() {
+        let path = temp_path("top_down.bmp");
+        let out_path = temp_path("top_down.out.bmp");
+
+        let mut data = header_bytes(4, -4i32 as u32, 24);
+        for i in 0..16u8 {
+            data.extend_from_slice(&[i, i + 16, i + 32]);
+        }
+        set_file_size(&mut data);
+        fs::write(&path, &data).unwrap();
+
+        let bmp = BMP::read(&path).unwrap();
+        assert_eq!(bmp.height(), 4);
+        let top_left = bmp.pixel(0, 0);
+        assert_eq!((top_left.r, top_left.g, top_left.b), (32, 16, 0));
+        let bottom_right = bmp.pixel(3, 3);
+        assert_eq!(
+            (bottom_right.r, bottom_right.g, bottom_right.b),
+            (47, 31, 15)
+        );
+
+        bmp.write(&out_path).unwrap();
+        let written = fs::read(&out_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&out_path).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn row_reads_match_per_pixel_decoding() {
+        let (width, height) = (5u32, 3u32);
+        let row_size = 16;
+        let path = temp_path("rows.bmp");
+
+        let mut data = header_bytes(width, height, 24);
+        data.extend((0..row_size * height as usize).map(|i| (i * 31 + 7) as u8));
+        fs::write(&path, &data).unwrap();
+
+        let bmp = BMP::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        for y in 0..height {
+            for x in 0..width {
+                let row = (height - 1 - y) as usize;
+                let pos = 54 + row * row_size + x as usize * 3;
+                let pixel = bmp.pixel(x, y);
+                assert_eq!(
+                    (pixel.b, pixel.g, pixel.r),
+                    (data[pos], data[pos + 1], data[pos + 2])
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn read_from_decodes_in_memory_buffer() {
+        let mut data = header_bytes(2, 2, 24);
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0, 0, 7, 8, 9, 10, 11, 12, 0, 0]);
+
+        let bmp = BMP::read_from(Cursor::new(data)).unwrap();
+        assert_eq!((bmp.width(), bmp.height()), (2, 2));
+        let top_left = bmp.pixel(0, 0);
+        assert_eq!((top_left.r, top_left.g, top_left.b), (9, 8, 7));
+        let bottom_right = bmp.pixel(1, 1);
+        assert_eq!((bottom_right.r, bottom_right.g, bottom_right.b), (6, 5, 4));
+    }
+
+    #[test]
+    fn write_to_round_trips_through_memory() {
+        let path = temp_path("write_to.bmp");
+        let mut data = header_bytes(3, 2, 24);
+        data.extend((0..24).map(|i| i * 9));
+        fs::write(&path, &data).unwrap();
+        let bmp = BMP::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut buf = Vec::new();
+        bmp.write_to(&mut buf).unwrap();
+        let reread = BMP::read_from(Cursor::new(buf)).unwrap();
+
+        assert_eq!((reread.width(), reread.height()), (3, 2));
+        for (a, b) in bmp.pixels.iter().zip(&reread.pixels) {
+            assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+        }
+    }
+
+    #[test]
+    fn write_without_row_padding() {
+        let path = temp_path("no_padding.bmp");
+        let out_path = temp_path("no_padding.out.bmp");
+        let mut data = header_bytes(4, 2, 24);
+        data.extend((0..24).map(|i| i * 3));
+        set_file_size(&mut data);
+        fs::write(&path, &data).unwrap();
+
+        BMP::read(&path).unwrap().write(&out_path).unwrap();
+        let written = fs::read(&out_path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&out_path).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn v5_header_round_trips() {
+        let mut data = header_bytes(2, 2, 24);
+        set_u32(&mut data, 10, 14 + 124 + 4);
+        set_u32(&mut data, 14, 124);
+        data.extend((0..84).map(|i| i as u8 ^ 0x5A));
+        data.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0, 0, 7, 8, 9, 10, 11, 12, 0, 0]);
+        set_file_size(&mut data);
+
+        let bmp = BMP::read_from(Cursor::new(data.clone())).unwrap();
+        let top_left = bmp.pixel(0, 0);
+        assert_eq!((top_left.r, top_left.g, top_left.b), (9, 8, 7));
+
+        let mut written = Vec::new();
+        bmp.write_to(&mut written).unwrap();
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn new_creates_valid_carrier() {
+        let path = temp_path("new.bmp");
+        let mut bmp = BMP::new(64, 64);
+        bmp.pixel_mut(10, 20).r = 0x12;
+        bmp.write(&path).unwrap();
+
+        let len = fs::metadata(&path).unwrap().len();
+        let bmp = BMP::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(len, 54 + 64 * 64 * 3);
+        assert_eq!(bmp.offset + 64 * BMP::row_size(24, 64), len as u32);
+        assert_eq!((bmp.width(), bmp.height()), (64, 64));
+        let pixel = bmp.pixel(10, 20);
+        assert_eq!((pixel.r, pixel.g, pixel.b), (0x12, 0xFF, 0xFF));
+        let pixel = bmp.pixel(63, 63);
+        assert_eq!((pixel.r, pixel.g, pixel.b), (0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn flips_move_corner_pixel() {
+        let marker = Pixel {
+            r: 0xff,
+            g: 0,
+            b: 0,
+            a: 0xff,
+        };
+        let mut bmp = BMP::new(4, 3);
+        *bmp.pixel_mut(0, 0) = marker;
+
+        bmp.flip_vertical();
+        assert_eq!(bmp.pixel(0, 2), marker);
+        assert_ne!(bmp.pixel(0, 0), marker);
+
+        bmp.flip_horizontal();
+        assert_eq!(bmp.pixel(3, 2), marker);
+        assert_ne!(bmp.pixel(0, 2), marker);
+    }
+
+    #[test]
+    fn to_grayscale_uses_rec601_luma() {
+        let mut bmp = BMP::filled(
+            2,
+            1,
+            Pixel {
+                r: 100,
+                g: 150,
+                b: 200,
+                a: 0x80,
+            },
+        );
+        bmp.pixel_mut(1, 0).r = 255;
+        bmp.to_grayscale();
+
+        assert_eq!(
+            bmp.pixel(0, 0),
+            Pixel {
+                r: 141,
+                g: 141,
+                b: 141,
+                a: 0x80
+            }
+        );
+        let pixel = bmp.pixel(1, 0);
+        assert_eq!((pixel.r, pixel.g, pixel.b), (187, 187, 187));
+    }
+
+    #[test]
+    fn diff_highlights_changed_pixels() {
+        let gray = Pixel {
+            r: 0x80,
+            g: 0x80,
+            b: 0x80,
+            a: 0xFF,
+        };
+        let carrier = BMP::filled(16, 16, gray);
+        let mut stream = ImageDataStream::new(BMP::filled(16, 16, gray));
+        stream.embed_bytes(b"spot the difference").unwrap();
+        let stego = stream.into_inner();
+
+        let diff = carrier.diff(&stego).unwrap();
+        let black = |p: Pixel| (p.r, p.g, p.b) == (0, 0, 0);
+        for (x, y, pixel) in diff.pixels() {
+            assert_eq!(!black(pixel), carrier.pixel(x, y) != stego.pixel(x, y));
+        }
+        assert!(diff.pixels().any(|(_, _, p)| !black(p)));
+
+        assert!(carrier.diff(&BMP::new(16, 8)).is_err());
+    }
+
+    #[test]
+    fn psnr_measures_distortion() {
+        let original = BMP::new(8, 8);
+        assert_eq!(original.psnr(&original).unwrap(), f64::INFINITY);
+
+        let mut changed = BMP::new(8, 8);
+        changed.pixel_mut(3, 4).b ^= 1;
+        let psnr = original.psnr(&changed).unwrap();
+        assert!(psnr.is_finite() && psnr > 60.0, "psnr {}", psnr);
+
+        assert!(original.psnr(&BMP::new(8, 4)).is_err());
+    }
+
+    #[test]
+    fn crop_extracts_sub_rectangle() {
+        let mut bmp = BMP::new(4, 4);
+        for (i, pixel) in bmp.pixels.iter_mut().enumerate() {
+            pixel.r = i as u8;
+        }
+
+        let cropped = bmp.crop(1, 2, 2, 2).unwrap();
+        assert_eq!((cropped.width(), cropped.height()), (2, 2));
+        let reds: Vec<u8> = cropped.pixels().map(|(_, _, p)| p.r).collect();
+        assert_eq!(reds, [9, 10, 13, 14]);
+
+        let mut buf = Vec::new();
+        cropped.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), 54 + 2 * 8);
+        let reread = BMP::read_from(Cursor::new(buf)).unwrap();
+        assert_eq!(reread.pixels, cropped.pixels);
+
+        assert!(bmp.crop(3, 0, 2, 1).is_err());
+        assert!(bmp.crop(0, u32::MAX, 1, 2).is_err());
+    }
+
+    #[test]
+    fn header_reports_fixture_properties() {
+        let mut data = header_bytes(3, 2, 32);
+        data.extend_from_slice(&[0; 3 * 2 * 4]);
+
+        let header = BMP::read_from(Cursor::new(data)).unwrap().header();
+        assert_eq!(
+            header,
+            BmpHeader {
+                width: 3,
+                height: 2,
+                bpp: 32,
+                compression: 0,
+                h_ppm: 2835,
+                v_ppm: 2835,
+                num_colors: 0,
+                used_colors: 0,
+                is_top_down: false,
+            }
+        );
+    }
+
+    #[test]
+    fn dpi_round_trips_through_ppm() {
+        let mut bmp = BMP::new(2, 2);
+        let (x, y) = bmp.dpi();
+        assert!(
+            (x - 72.0).abs() < 0.01 && (y - 72.0).abs() < 0.01,
+            "{} {}",
+            x,
+            y
+        );
+
+        bmp.set_dpi(300.0, 150.0);
+        let mut buf = Vec::new();
+        bmp.write_to(&mut buf).unwrap();
+        let (x, y) = BMP::read_from(Cursor::new(buf)).unwrap().dpi();
+        assert!((x - 300.0).abs() < 0.02, "{}", x);
+        assert!((y - 150.0).abs() < 0.02, "{}", y);
+    }
+
+    #[test]
+    fn embedding_preserves_header_fields() {
+        let mut data = header_bytes(16, 16, 24);
+        set_u32(&mut data, 38, 3780);
+        set_u32(&mut data, 42, 1234);
+        set_u32(&mut data, 46, 16);
+        set_u32(&mut data, 50, 7);
+        data.extend((0..16 * 16 * 3).map(|i| (i * 7) as u8));
+
+        let mut stream = ImageDataStream::new(BMP::read_from(Cursor::new(data.clone())).unwrap());
+        stream.embed_bytes(b"header fields").unwrap();
+        let mut written = Vec::new();
+        stream.into_inner().write_to(&mut written).unwrap();
+
+        let fixed = |bytes: &[u8]| {
+            let mut header = bytes[..54].to_vec();
+            header[2..6].fill(0);
+            header[34..38].fill(0);
+            header
+        };
+        assert_eq!(fixed(&written), fixed(&data));
+        assert_ne!(written[54..], data[54..]);
+    }
+
+    #[test]
+    fn unusual_ppm_values_round_trip() {
+        let mut data = header_bytes(3, 2, 24);
+        data[38..42].copy_from_slice(&(-2835i32).to_le_bytes());
+        data[42..46].copy_from_slice(&0i32.to_le_bytes());
+        for _ in 0..2 {
+            data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 0, 0]);
+        }
+        set_file_size(&mut data);
+
+        let bmp = BMP::from_bytes(&data).unwrap();
+        assert_eq!((bmp.header().h_ppm, bmp.header().v_ppm), (-2835, 0));
+        assert!(bmp.dpi().0 < 0.0);
+        assert_eq!(bmp.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn write_emits_bi_rgb_only() {
+        let bytes = BMP::new(3, 3).to_bytes().unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[30..34].try_into().unwrap()), 0);
+
+        let mut bmp = BMP::new(3, 3);
+        bmp.compression = 2;
+        assert!(matches!(bmp.to_bytes(), Err(StegError::Compressed(2))));
+    }
+
+    #[test]
+    fn write_fills_in_zero_image_size() {
+        let mut data = header_bytes(3, 2, 24);
+        set_u32(&mut data, 34, 0);
+        data.extend_from_slice(&[0; 2 * 12]);
+        set_file_size(&mut data);
+
+        let written = BMP::from_bytes(&data).unwrap().to_bytes().unwrap();
+        assert_eq!(u32::from_le_bytes(written[34..38].try_into().unwrap()), 24);
+    }
+
+    #[test]
+    fn write_persists_complete_file() {
+        let path = temp_path("synced.bmp");
+        BMP::new(9, 4).write(&path).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(len, 54 + 4 * BMP::row_size(24, 9) as u64);
+    }
+
+    #[test]
+    fn write_truncates_existing_file() {
+        let path = temp_path("overwrite.bmp");
+        fs::write(&path, vec![0xaa; 4096]).unwrap();
+
+        let bmp = BMP::new(2, 2);
+        bmp.write(&path).unwrap();
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written, bmp.to_bytes().unwrap());
+    }
+
+    #[test]
+    fn interrupted_atomic_write_leaves_target_unchanged() {
+        let path = temp_path("atomic.bmp");
+        let original = BMP::new(3, 3);
+        original.write(&path).unwrap();
+        let before = fs::read(&path).unwrap();
+
+        let bmp = BMP::new(6, 2);
+        let temp = bmp.write_temp(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), before);
+        assert_eq!(temp.parent(), path.parent());
+        fs::remove_file(&temp).unwrap();
+
+        bmp.write_atomic(&path).unwrap();
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written, bmp.to_bytes().unwrap());
+        assert!(!temp.exists());
+    }
+
+    #[test]
+    fn write_records_actual_file_size() {
+        let path = temp_path("size_field.bmp");
+        BMP::new(5, 3).write(&path).unwrap();
+
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let size = u32::from_le_bytes(written[2..6].try_into().unwrap());
+        assert_eq!(size as usize, written.len());
+    }
+
+    #[test]
+    fn write_rejects_sizes_past_header_fields() {
+        // Only the dimensions matter, since the header is checked before
+        // any pixel is written.
+        let mut bmp = BMP::new(1, 1);
+        (bmp.width, bmp.height) = (40000, 36000);
+        assert!(matches!(
+            bmp.to_bytes(),
+            Err(StegError::TooLarge {
+                width: 40000,
+                height: 36000,
+                ..
+            })
+        ));
+
+        (bmp.width, bmp.height) = (0, 1 << 31);
+        assert!(matches!(bmp.to_bytes(), Err(StegError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn odd_widths_round_trip_payload() {
+        for width in [1, 2, 3, 5] {
+            let mut bmp = BMP::new(width, 64 / width);
+            for (i, pixel) in bmp.pixels.iter_mut().enumerate() {
+                *pixel = Pixel {
+                    r: i as u8,
+                    g: (i * 7) as u8,
+                    b: (i * 13) as u8,
+                    a: 0xff,
+                };
+            }
+            let mut stream = ImageDataStream::new(bmp);
+            stream.embed_bytes(b"pad").unwrap();
+            let bytes = stream.into_inner().to_bytes().unwrap();
+            assert_eq!(
+                bytes.len() as u32,
+                54 + (64 / width) * BMP::row_size(24, width)
+            );
+
+            let reread = ImageDataStream::new(BMP::read_from(Cursor::new(bytes)).unwrap());
+            assert_eq!(reread.extract_bytes().unwrap(), b"pad", "width {}", width);
+        }
+    }
+
+    #[test]
+    fn from_bytes_matches_read() {
+        let path = temp_path("from_bytes.bmp");
+        let mut bmp = BMP::new(7, 5);
+        bmp.pixel_mut(6, 4).g = 0x42;
+        bmp.write(&path).unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let from_file = BMP::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let from_bytes = BMP::from_bytes(&data).unwrap();
+        assert_eq!(from_bytes.header(), from_file.header());
+        assert_eq!(from_bytes.pixels, from_file.pixels);
+        assert_eq!(from_bytes.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn into_bytes_round_trips_payload() {
+        let mut stream = ImageDataStream::new(BMP::new(16, 8));
+        stream.embed_bytes(b"in memory").unwrap();
+        let bytes = stream.into_inner().into_bytes().unwrap();
+
+        let reread = ImageDataStream::new(BMP::read_from(Cursor::new(bytes)).unwrap());
+        assert_eq!(reread.extract_bytes().unwrap(), b"in memory");
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png_export_preserves_payload() {
+        let path = temp_path("export.png");
+        let mut stream = ImageDataStream::new(BMP::new(12, 10));
+        stream.embed_bytes(b"lossless").unwrap();
+        stream.into_inner().write_png(&path).unwrap();
+
+        let mut decoder = png::Decoder::new(BufReader::new(fs::File::open(&path).unwrap()));
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+
+        let mut bmp = BMP::new(info.width, info.height);
+        for (pixel, rgb) in bmp.pixels.iter_mut().zip(buf.chunks_exact(3)) {
+            (pixel.r, pixel.g, pixel.b) = (rgb[0], rgb[1], rgb[2]);
+        }
+        let stream = ImageDataStream::new(bmp);
+        assert_eq!(stream.extract_bytes().unwrap(), b"lossless");
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png_carrier_round_trips_payload() {
+        let carrier_path = temp_path("carrier.png");
+        let stego_path = temp_path("stego.png");
+        BMP::filled(
+            9,
+            9,
+            Pixel {
+                r: 0x80,
+                g: 0x40,
+                b: 0x20,
+                a: 0xFF,
+            },
+        )
+        .write_png(&carrier_path)
+        .unwrap();
+
+        let mut stream = ImageDataStream::new(BMP::read_png(&carrier_path).unwrap());
+        stream.embed_bytes(b"portable").unwrap();
+        stream.into_inner().write_png(&stego_path).unwrap();
+
+        let stream = ImageDataStream::new(BMP::read_png(&stego_path).unwrap());
+        fs::remove_file(&carrier_path).unwrap();
+        fs::remove_file(&stego_path).unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"portable");
+    }
+
+    #[test]
+    fn row_size_pads_bits_to_words() {
+        for (bpp, width, expected) in [
+            (1, 1, 4),
+            (1, 32, 4),
+            (1, 33, 8),
+            (4, 7, 4),
+            (4, 9, 8),
+            (8, 3, 4),
+            (8, 5, 8),
+            (24, 1, 4),
+            (24, 3, 12),
+            (24, 5, 16),
+        ] {
+            assert_eq!(
+                BMP::row_size(bpp, width),
+                expected,
+                "{}bpp x {}",
+                bpp,
+                width
+            );
+        }
+    }
+
+    #[test]
+    fn pixels_slice_covers_image_in_row_major_order() {
+        let mut bmp = BMP::new(5, 3);
+        assert_eq!(bmp.pixels_slice().len(), 5 * 3);
+
+        bmp.pixels_slice_mut()[5 + 2].r = 0x12;
+        assert_eq!(bmp.pixel(2, 1).r, 0x12);
+        assert_eq!(bmp.pixels_slice()[7], bmp.pixel(2, 1));
+    }
+}
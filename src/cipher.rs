@@ -0,0 +1,121 @@
+use sha2::{Digest, Sha256};
+use std::io;
+
+use crate::error::Result;
+
+// Headers from before version 7 carry no salt and were all keyed with this.
+const LEGACY_SALT: &[u8] = b"lab2 stego key";
+const KDF_ROUNDS: u32 = 10_000;
+
+pub(crate) const SALT_LEN: usize = 16;
+
+fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key: [u8; 32] = Sha256::new()
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize()
+        .into();
+    for _ in 0..KDF_ROUNDS {
+        key = Sha256::new()
+            .chain_update(key)
+            .chain_update(password)
+            .finalize()
+            .into();
+    }
+
+    key
+}
+
+// XORs `data` with a SHA-256 counter-mode keystream, so the same call
+// both encrypts and decrypts. A fresh salt per embedding gives each one its
+// own keystream.
+pub(crate) fn apply_keystream(password: &str, salt: Option<&[u8; SALT_LEN]>, data: &mut [u8]) {
+    let salt = salt.map_or(LEGACY_SALT, |salt| &salt[..]);
+    xor_keystream(&derive_key(password, salt), data);
+}
+
+pub(crate) fn random_salt() -> Result<[u8; SALT_LEN]> {
+    let mut salt = [0; SALT_LEN];
+    fill_random(&mut salt)?;
+    Ok(salt)
+}
+
+fn xor_keystream(key: &[u8; 32], data: &mut [u8]) {
+    for (counter, chunk) in data.chunks_mut(32).enumerate() {
+        let block = Sha256::new()
+            .chain_update(key)
+            .chain_update((counter as u64).to_le_bytes())
+            .finalize();
+        for (byte, k) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= k;
+        }
+    }
+}
+
+// HMAC-SHA256 as in RFC 2104, keyed with the password bytes.
+pub(crate) fn hmac(key: &str, data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key.as_bytes());
+    }
+
+    let inner = Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x36))
+        .chain_update(data)
+        .finalize();
+    Sha256::new()
+        .chain_update(block.map(|byte| byte ^ 0x5C))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+// Compares every byte so the time taken doesn't reveal how much matched.
+pub(crate) fn verify_hmac(key: &str, data: &[u8], mac: &[u8; 32]) -> bool {
+    let expected = hmac(key, data);
+    expected
+        .iter()
+        .zip(mac)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+pub(crate) fn random_bytes(len: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0; len];
+    fill_random(&mut data)?;
+    Ok(data)
+}
+
+// Bytes from the operating system's cryptographic RNG.
+fn fill_random(buf: &mut [u8]) -> Result<()> {
+    getrandom::fill(buf).map_err(io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_matches_rfc_4231_vector() {
+        let mac = hmac("Jefe", b"what do ya want for nothing?");
+        let hex: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(
+            hex,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(verify_hmac("Jefe", b"what do ya want for nothing?", &mac));
+        assert!(!verify_hmac("Jeff", b"what do ya want for nothing?", &mac));
+    }
+
+    #[test]
+    fn random_bytes_differ_between_calls() {
+        let (a, b) = (random_bytes(32).unwrap(), random_bytes(32).unwrap());
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, b);
+    }
+}
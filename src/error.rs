@@ -0,0 +1,65 @@
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StegError {
+    #[error("not a BMP file: bad magic {0:#06x}")]
+    BadMagic(u16),
+    #[error("invalid BMP: num_planes must be 1, got {0}")]
+    InvalidPlanes(u16),
+    #[error("unsupported bpp {0}")]
+    UnsupportedBpp(u16),
+    #[error("unsupported BMP info header size {0}")]
+    UnsupportedHeaderSize(u32),
+    #[error("compressed BMP (method {0}) not supported")]
+    Compressed(u32),
+    #[error("image of {width}x{height} pixels exceeds limit of {limit} pixels")]
+    TooLarge { width: u32, height: u32, limit: u64 },
+    #[error("payload of {bytes} bytes exceeds capacity {capacity}")]
+    PayloadTooLarge { bytes: u64, capacity: u64 },
+    #[error("carrier too small: needs at least {needed} pixels, has {pixels}")]
+    CarrierTooSmall { pixels: u64, needed: u64 },
+    #[error("payload of {bytes} bytes exceeds maximum length {max}")]
+    LengthOverflow { bytes: u64, max: u64 },
+    #[error("payload checksum mismatch: corrupted or not a stego image")]
+    ChecksumMismatch,
+    #[error("truncated file: unexpected end of data")]
+    Truncated,
+    #[error("truncated BMP: expected {expected} pixels, got {got}")]
+    TruncatedPixels { expected: u64, got: u64 },
+    #[error("no stego signature found")]
+    NoPayload,
+    #[error("payload is not a file archive")]
+    NotArchive,
+    #[error("payload is encrypted, password required")]
+    PasswordRequired,
+    #[error("payload is shuffled, seed required")]
+    SeedRequired,
+    #[error("payload is authenticated, key required")]
+    AuthKeyRequired,
+    #[error("payload authentication failed: tampered or wrong key")]
+    AuthenticationFailed,
+    #[error("{0}")]
+    Corrupted(String),
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[cfg(feature = "png")]
+    #[error("PNG error: {0}")]
+    Png(String),
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+// Short reads surface as `UnexpectedEof` from every reader in the crate,
+// so they are folded into `Truncated` here rather than at each call site.
+impl From<io::Error> for StegError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            StegError::Truncated
+        } else {
+            StegError::Io(err)
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StegError>;
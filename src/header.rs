@@ -0,0 +1,278 @@
+use crate::bits::{WordPacker, WordUnpacker};
+use crate::cipher::SALT_LEN;
+use crate::error::{Result, StegError};
+use crate::stream::ChannelBits;
+
+#[derive(Debug)]
+pub(crate) struct Header {
+    pub bytes: u64,
+    pub bits: ChannelBits,
+    pub filename: Option<String>,
+    pub crc: Option<u32>,
+    pub flags: u8,
+    pub start: u64,
+    pub salt: Option<[u8; SALT_LEN]>,
+    pub mac: Option<[u8; 32]>,
+}
+
+impl Header {
+    const LENGTH_BITS: u8 = 63;
+    const CONFIG_BITS: u8 = 9;
+    const VERSION_BITS: u8 = 5;
+    const NAME_LEN_BITS: u8 = 8;
+    const CRC_BITS: u8 = 32;
+    const MAGIC_BITS: u8 = 32;
+    const FLAGS_BITS: u8 = 8;
+    const START_BITS: u8 = 32;
+
+    // Version 0 headers carry only the length and channel configuration,
+    // version 1 adds a length-prefixed UTF-8 filename, version 2 adds a
+    // CRC32 of the payload, version 3 adds a signature right after the
+    // version tag, version 4 adds payload flags after the signature and
+    // version 5 adds the offset of the data past the header, version 6
+    // adds an HMAC after the CRC, present only with the AUTHENTICATED flag,
+    // and version 7 adds a key salt between the two, present only with the
+    // ENCRYPTED flag; the CRC of a salted payload covers the ciphertext.
+    const VERSION: u64 = 7;
+    const MAGIC: u64 = 0x4745_5453; // "STEG"
+
+    pub const ENCRYPTED: u8 = 1 << 0;
+    pub const COMPRESSED: u8 = 1 << 1;
+    pub const SHUFFLED: u8 = 1 << 2;
+    pub const ARCHIVE: u8 = 1 << 3;
+    pub const ADAPTIVE: u8 = 1 << 4;
+    pub const AUTHENTICATED: u8 = 1 << 5;
+
+    pub const MAX_START: u64 = (1 << Self::START_BITS) - 1;
+
+    pub const MAX_FILENAME_LEN: usize = (1 << Self::NAME_LEN_BITS) - 1;
+
+    // The length comes before the version tag, so it can't be widened
+    // without breaking every existing header; payloads are capped instead.
+    pub const MAX_LENGTH: u64 = (1 << Self::LENGTH_BITS) - 1;
+
+    /// Words taken by a header without a filename, the smallest a carrier
+    /// can hold.
+    pub const fn min_words(word_size: u8) -> u64 {
+        let bits = Self::LENGTH_BITS as u64
+            + Self::CONFIG_BITS as u64
+            + Self::VERSION_BITS as u64
+            + Self::MAGIC_BITS as u64
+            + Self::FLAGS_BITS as u64
+            + Self::START_BITS as u64
+            + Self::NAME_LEN_BITS as u64
+            + Self::CRC_BITS as u64;
+        bits.div_ceil(word_size as u64)
+    }
+
+    pub fn encode(&self, word_size: u8) -> Vec<u8> {
+        // Callers check lengths against MAX_LENGTH; pushing more would drop
+        // the top bit silently.
+        debug_assert!(self.bytes <= Self::MAX_LENGTH);
+        let mut packer = WordPacker::new(word_size);
+        packer.push(self.bytes, Self::LENGTH_BITS);
+        packer.push(self.bits.encode(), Self::CONFIG_BITS);
+        packer.push(Self::VERSION, Self::VERSION_BITS);
+        packer.push(Self::MAGIC, Self::MAGIC_BITS);
+        packer.push(self.flags as u64, Self::FLAGS_BITS);
+        packer.push(self.start, Self::START_BITS);
+
+        let name = self.filename.as_deref().unwrap_or("");
+        packer.push(name.len() as u64, Self::NAME_LEN_BITS);
+        for byte in name.bytes() {
+            packer.push(byte as u64, 8);
+        }
+        packer.push(self.crc.unwrap_or(0) as u64, Self::CRC_BITS);
+        let salt = self.salt.iter().flatten();
+        for byte in salt.chain(self.mac.iter().flatten()) {
+            packer.push(*byte as u64, 8);
+        }
+
+        packer.into_words()
+    }
+
+    pub fn decode(word_size: u8, next_word: impl FnMut() -> Result<u8>) -> Result<Self> {
+        let mut unpacker = WordUnpacker::new(word_size, next_word);
+        let bytes = unpacker.take(Self::LENGTH_BITS)?;
+        let bits = ChannelBits::decode(unpacker.take(Self::CONFIG_BITS)?)?;
+        let version = unpacker.take(Self::VERSION_BITS)?;
+        if version > Self::VERSION {
+            return Err(StegError::Corrupted(format!(
+                "unsupported stego header version {}",
+                version
+            )));
+        }
+        if version >= 3 && unpacker.take(Self::MAGIC_BITS)? != Self::MAGIC {
+            return Err(StegError::NoPayload);
+        }
+
+        let mut flags = 0;
+        if version >= 4 {
+            flags = unpacker.take(Self::FLAGS_BITS)? as u8;
+        }
+
+        let mut start = 0;
+        if version >= 5 {
+            start = unpacker.take(Self::START_BITS)?;
+        }
+
+        let mut filename = None;
+        if version >= 1 {
+            let len = unpacker.take(Self::NAME_LEN_BITS)?;
+            let mut name = Vec::new();
+            for _ in 0..len {
+                name.push(unpacker.take(8)? as u8);
+            }
+
+            if !name.is_empty() {
+                let name = String::from_utf8(name).map_err(|_| {
+                    StegError::Corrupted("corrupted stego header: filename is not UTF-8".into())
+                })?;
+                filename = Some(name);
+            }
+        }
+
+        let mut crc = None;
+        if version >= 2 {
+            crc = Some(unpacker.take(Self::CRC_BITS)? as u32);
+        }
+
+        let mut salt = None;
+        if version >= 7 && flags & Self::ENCRYPTED != 0 {
+            let mut bytes = [0; SALT_LEN];
+            for byte in &mut bytes {
+                *byte = unpacker.take(8)? as u8;
+            }
+            salt = Some(bytes);
+        }
+
+        let mut mac = None;
+        if version >= 6 && flags & Self::AUTHENTICATED != 0 {
+            let mut tag = [0; 32];
+            for byte in &mut tag {
+                *byte = unpacker.take(8)? as u8;
+            }
+            mac = Some(tag);
+        }
+
+        Ok(Self {
+            bytes,
+            bits,
+            filename,
+            crc,
+            flags,
+            start,
+            salt,
+            mac,
+        })
+    }
+
+    pub fn has_signature(word_size: u8, next_word: impl FnMut() -> Result<u8>) -> Result<bool> {
+        let mut unpacker = WordUnpacker::new(word_size, next_word);
+        unpacker.take(Self::LENGTH_BITS)?;
+        unpacker.take(Self::CONFIG_BITS)?;
+        let version = unpacker.take(Self::VERSION_BITS)?;
+
+        Ok(version >= 3 && unpacker.take(Self::MAGIC_BITS)? == Self::MAGIC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORD_SIZE: u8 = ChannelBits::DEFAULT.word_size();
+
+    #[test]
+    fn decodes_version_0_header_without_filename() {
+        let mut packer = WordPacker::new(WORD_SIZE);
+        packer.push(1234, Header::LENGTH_BITS);
+        packer.push(ChannelBits::DEFAULT.encode(), Header::CONFIG_BITS);
+        packer.push(0, Header::VERSION_BITS);
+        let mut words = packer.into_words().into_iter();
+
+        let header = Header::decode(WORD_SIZE, || Ok(words.next().unwrap())).unwrap();
+        assert_eq!(header.bytes, 1234);
+        assert_eq!(header.bits, ChannelBits::DEFAULT);
+        assert_eq!(header.filename, None);
+        assert_eq!(header.crc, None);
+        assert_eq!(header.flags, 0);
+        assert_eq!(header.start, 0);
+        assert!(words.next().is_none());
+    }
+
+    #[test]
+    fn mac_round_trips_with_flag() {
+        let header = Header {
+            bytes: 5,
+            bits: ChannelBits::DEFAULT,
+            filename: None,
+            crc: Some(0),
+            flags: Header::AUTHENTICATED,
+            start: 0,
+            salt: None,
+            mac: Some([0xA5; 32]),
+        };
+        let mut words = header.encode(WORD_SIZE).into_iter();
+
+        let decoded = Header::decode(WORD_SIZE, || Ok(words.next().unwrap())).unwrap();
+        assert_eq!(decoded.mac, Some([0xA5; 32]));
+        assert!(words.next().is_none());
+    }
+
+    #[test]
+    fn salt_round_trips_with_flag() {
+        let header = Header {
+            bytes: 5,
+            bits: ChannelBits::DEFAULT,
+            filename: None,
+            crc: Some(0),
+            flags: Header::ENCRYPTED | Header::AUTHENTICATED,
+            start: 0,
+            salt: Some([0x3C; SALT_LEN]),
+            mac: Some([0xA5; 32]),
+        };
+        let mut words = header.encode(WORD_SIZE).into_iter();
+
+        let decoded = Header::decode(WORD_SIZE, || Ok(words.next().unwrap())).unwrap();
+        assert_eq!(decoded.salt, Some([0x3C; SALT_LEN]));
+        assert_eq!(decoded.mac, Some([0xA5; 32]));
+        assert!(words.next().is_none());
+    }
+
+    #[test]
+    fn max_length_round_trips() {
+        let header = Header {
+            bytes: Header::MAX_LENGTH,
+            bits: ChannelBits::DEFAULT,
+            filename: None,
+            crc: Some(0),
+            flags: 0,
+            start: 0,
+            salt: None,
+            mac: None,
+        };
+        let mut words = header.encode(WORD_SIZE).into_iter();
+
+        let decoded = Header::decode(WORD_SIZE, || Ok(words.next().unwrap())).unwrap();
+        assert_eq!(decoded.bytes, Header::MAX_LENGTH);
+    }
+
+    #[test]
+    fn min_words_matches_encoded_length() {
+        let header = Header {
+            bytes: 1,
+            bits: ChannelBits::DEFAULT,
+            filename: None,
+            crc: Some(0),
+            flags: 0,
+            start: 0,
+            salt: None,
+            mac: None,
+        };
+        assert_eq!(
+            Header::min_words(WORD_SIZE),
+            header.encode(WORD_SIZE).len() as u64
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use crate::error::{Result, StegError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pixel {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Pixel {
+    /// Rec. 601 luma, rounded to the nearest integer.
+    pub fn luminance(&self) -> u8 {
+        ((299 * self.r as u32 + 587 * self.g as u32 + 114 * self.b as u32 + 500) / 1000) as u8
+    }
+
+    /// Sum of the colour channels, ignoring alpha.
+    pub fn channel_sum(&self) -> u32 {
+        self.r as u32 + self.g as u32 + self.b as u32
+    }
+}
+
+pub trait Image {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn pixel(&self, x: u32, y: u32) -> Pixel;
+    fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel;
+
+    /// Whether the alpha byte of each pixel is stored, rather than implied.
+    fn has_alpha(&self) -> bool {
+        false
+    }
+
+    /// Every pixel as one row-major slice, if the image stores them that way.
+    fn contiguous_pixels_mut(&mut self) -> Option<&mut [Pixel]> {
+        None
+    }
+
+    fn try_pixel(&self, x: u32, y: u32) -> Option<Pixel> {
+        if x >= self.width() || y >= self.height() {
+            return None;
+        }
+        Some(self.pixel(x, y))
+    }
+
+    fn try_pixel_mut(&mut self, x: u32, y: u32) -> Option<&mut Pixel> {
+        if x >= self.width() || y >= self.height() {
+            return None;
+        }
+        Some(self.pixel_mut(x, y))
+    }
+
+    /// Yields every pixel with its coordinates in row-major order.
+    fn pixels(&self) -> impl Iterator<Item = (u32, u32, Pixel)> + '_
+    where
+        Self: Sized,
+    {
+        let width = self.width();
+        (0..self.height()).flat_map(move |y| (0..width).map(move |x| (x, y, self.pixel(x, y))))
+    }
+}
+
+impl<I: Image + ?Sized> Image for Box<I> {
+    fn width(&self) -> u32 {
+        (**self).width()
+    }
+
+    fn height(&self) -> u32 {
+        (**self).height()
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> Pixel {
+        (**self).pixel(x, y)
+    }
+
+    fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
+        (**self).pixel_mut(x, y)
+    }
+
+    fn has_alpha(&self) -> bool {
+        (**self).has_alpha()
+    }
+
+    fn contiguous_pixels_mut(&mut self) -> Option<&mut [Pixel]> {
+        (**self).contiguous_pixels_mut()
+    }
+}
+
+pub(crate) fn check_same_size(a: &impl Image, b: &impl Image) -> Result<()> {
+    if (a.width(), a.height()) != (b.width(), b.height()) {
+        return Err(StegError::InvalidArgument(format!(
+            "image dimensions differ: {}x{} vs {}x{}",
+            a.width(),
+            a.height(),
+            b.width(),
+            b.height()
+        )));
+    }
+    Ok(())
+}
+
+/// Pixels in row-major order, for feeding images decoded by other
+/// libraries straight into a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<Pixel>,
+}
+
+impl RawImage {
+    pub fn new(width: u32, height: u32, data: Vec<Pixel>) -> Result<Self> {
+        if data.len() as u64 != width as u64 * height as u64 {
+            return Err(StegError::InvalidArgument(format!(
+                "{} pixels don't fill a {}x{} image",
+                data.len(),
+                width,
+                height
+            )));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            data,
+        })
+    }
+}
+
+impl Image for RawImage {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> Pixel {
+        self.data[y as usize * self.width as usize + x as usize]
+    }
+
+    fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
+        &mut self.data[y as usize * self.width as usize + x as usize]
+    }
+
+    fn contiguous_pixels_mut(&mut self) -> Option<&mut [Pixel]> {
+        Some(&mut self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::ImageDataStream;
+    use crate::test_util::TestImage;
+
+    #[test]
+    fn luminance_and_channel_sum() {
+        let red = Pixel {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 255,
+        };
+        assert_eq!(red.luminance(), 76);
+        assert_eq!(red.channel_sum(), 255);
+
+        let white = Pixel {
+            r: 255,
+            g: 255,
+            b: 255,
+            a: 0,
+        };
+        assert_eq!(white.luminance(), 255);
+        assert_eq!(white.channel_sum(), 765);
+    }
+
+    #[test]
+    fn pixels_compare_by_value() {
+        let red = Pixel {
+            r: 255,
+            g: 0,
+            b: 0,
+            a: 255,
+        };
+        let copy = red;
+
+        assert_eq!(red, copy);
+        assert_ne!(red, Pixel { g: 1, ..red });
+    }
+
+    #[test]
+    fn pixels_iterates_in_row_major_order() {
+        let image = TestImage::new(2, 2);
+        let visited: Vec<(u32, u32, u8)> = image.pixels().map(|(x, y, p)| (x, y, p.r)).collect();
+
+        assert_eq!(visited, [(0, 0, 0), (1, 0, 1), (0, 1, 2), (1, 1, 3)]);
+    }
+
+    #[test]
+    fn try_pixel_checks_bounds() {
+        let mut image = TestImage::new(3, 2);
+        assert_eq!(image.try_pixel(2, 1).map(|p| p.r), Some(5));
+        assert!(image.try_pixel(3, 0).is_none());
+        assert!(image.try_pixel(0, 2).is_none());
+        assert!(image.try_pixel_mut(3, 2).is_none());
+
+        image.try_pixel_mut(0, 1).unwrap().g = 0x42;
+        assert_eq!(image.pixel(0, 1).g, 0x42);
+    }
+
+    #[test]
+    fn raw_image_round_trips_through_stream() {
+        let data = (0..32 * 16)
+            .map(|i| Pixel {
+                r: i as u8,
+                g: (i * 5) as u8,
+                b: (i * 11) as u8,
+                a: 0xFF,
+            })
+            .collect();
+        let image = RawImage::new(32, 16, data).unwrap();
+
+        let mut stream = ImageDataStream::new(image);
+        stream.embed_bytes(b"raw pixels").unwrap();
+
+        let stream = ImageDataStream::new(stream.into_inner());
+        assert_eq!(stream.extract_bytes().unwrap(), b"raw pixels");
+        assert!(RawImage::new(2, 2, Vec::new()).is_err());
+    }
+}
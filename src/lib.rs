@@ -0,0 +1,26 @@
+mod archive;
+mod bits;
+mod bmp;
+mod cipher;
+mod error;
+mod header;
+mod image;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod rng;
+mod stream;
+#[cfg(test)]
+mod test_util;
+
+pub use bits::{
+    BitOrder, BitReader, BitWriter, FileBitReader, FileBitWriter, MemBitReader, MemBitWriter,
+};
+pub use bmp::{BmpHeader, BMP};
+pub use error::StegError;
+pub use image::{Image, Pixel, RawImage};
+#[cfg(feature = "mmap")]
+pub use mmap::MmapImage;
+pub use stream::{
+    DynImageDataStream, EmbedMode, Fill, FitReport, ImageDataStream, ImageDataStreamBuilder,
+    CHANNEL_BLUE, CHANNEL_GREEN, CHANNEL_RED,
+};
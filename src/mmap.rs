@@ -0,0 +1,272 @@
+use byteorder::{ByteOrder, LE};
+use memmap2::Mmap;
+use std::{collections::HashMap, fs::File, path::Path};
+
+use crate::bmp::BMP;
+use crate::error::{Result, StegError};
+use crate::image::{Image, Pixel};
+
+/// An uncompressed BMP file that is memory-mapped and decoded a pixel at a
+/// time on access. The mapping is read-only: a row changed through
+/// `pixel_mut` is decoded into memory, and changes never reach the file.
+pub struct MmapImage {
+    map: Mmap,
+    offset: usize,
+    width: u32,
+    height: u32,
+    bpp: u16,
+    row_size: usize,
+    is_top_down: bool,
+    palette: Vec<Pixel>,
+    modified: HashMap<u32, Vec<Pixel>>,
+}
+
+impl MmapImage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only ever read, and like any mmap it relies
+        // on the file not being truncated by someone else while it is open.
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < 18 {
+            return Err(StegError::Truncated);
+        }
+
+        let magic = LE::read_u16(&map[0..]);
+        if magic != BMP::MAGIC {
+            return Err(StegError::BadMagic(magic));
+        }
+        let offset = LE::read_u32(&map[10..]) as usize;
+        let hdr_size = LE::read_u32(&map[14..]);
+        BMP::check_header_size(hdr_size)?;
+        let is_core = hdr_size == BMP::CORE_HEADER_SIZE;
+        if map.len() < 14 + hdr_size.min(BMP::INFO_HEADER_SIZE) as usize {
+            return Err(StegError::Truncated);
+        }
+        let hdr_size = hdr_size as usize;
+
+        // OS/2 core headers have 16-bit dimensions and end after bpp.
+        let (width, height, fields) = if is_core {
+            (
+                LE::read_u16(&map[18..]) as u32,
+                LE::read_u16(&map[20..]) as i32,
+                22,
+            )
+        } else {
+            (LE::read_u32(&map[18..]), LE::read_i32(&map[22..]), 26)
+        };
+        let num_planes = LE::read_u16(&map[fields..]);
+        if num_planes != 1 {
+            return Err(StegError::InvalidPlanes(num_planes));
+        }
+        let bpp = LE::read_u16(&map[fields + 2..]);
+        if bpp != 8 && bpp != 24 && bpp != 32 {
+            return Err(StegError::UnsupportedBpp(bpp));
+        }
+        let (compression, num_colors) = if is_core {
+            (BMP::BI_RGB, 0)
+        } else {
+            (LE::read_u32(&map[30..]), LE::read_u32(&map[46..]))
+        };
+        if compression != BMP::BI_RGB {
+            return Err(StegError::Compressed(compression));
+        }
+
+        let is_top_down = height < 0;
+        let height = height.unsigned_abs();
+        let (w, h) = (width as u64, height as u64);
+        if w.max(h).max(w * h) > BMP::MAX_PIXELS {
+            return Err(StegError::TooLarge {
+                width,
+                height,
+                limit: BMP::MAX_PIXELS,
+            });
+        }
+
+        let row_size = BMP::row_size(bpp, width) as usize;
+        if offset as u64 + h * row_size as u64 > map.len() as u64 {
+            return Err(StegError::TruncatedPixels {
+                expected: w * h,
+                got: (map.len().saturating_sub(offset) / row_size.max(1)) as u64 * w,
+            });
+        }
+
+        let mut palette = Vec::new();
+        if bpp == 8 {
+            let entries = if num_colors == 0 {
+                256
+            } else {
+                num_colors as usize
+            };
+            let entry_size = if is_core { 3 } else { 4 };
+            let start = 14 + hdr_size;
+            let table = map
+                .get(start..start + entries * entry_size)
+                .ok_or(StegError::Truncated)?;
+            for entry in table.chunks_exact(entry_size) {
+                palette.push(Pixel {
+                    r: entry[2],
+                    g: entry[1],
+                    b: entry[0],
+                    a: entry.get(3).copied().unwrap_or(0),
+                });
+            }
+        }
+
+        let image = Self {
+            map,
+            offset,
+            width,
+            height,
+            bpp,
+            row_size,
+            is_top_down,
+            palette,
+            modified: HashMap::new(),
+        };
+
+        // Pixels are decoded on access, where errors can't be returned, so a
+        // short palette is checked against every index up front.
+        if image.bpp == 8 && image.palette.len() < 256 {
+            let mut scratch = Vec::with_capacity(width as usize);
+            for y in 0..height {
+                scratch.clear();
+                BMP::decode_row(
+                    8,
+                    &image.palette,
+                    None,
+                    image.row_bytes(y),
+                    width,
+                    &mut scratch,
+                )?;
+            }
+        }
+
+        Ok(image)
+    }
+
+    fn row_bytes(&self, y: u32) -> &[u8] {
+        let row = if self.is_top_down {
+            y
+        } else {
+            self.height - 1 - y
+        };
+        let start = self.offset + row as usize * self.row_size;
+        &self.map[start..start + self.row_size]
+    }
+
+    fn decode(&self, x: u32, y: u32) -> Pixel {
+        let bytes_per_pixel = (self.bpp / 8) as usize;
+        let start = x as usize * bytes_per_pixel;
+        let chunk = &self.row_bytes(y)[start..start + bytes_per_pixel];
+        BMP::decode_pixel(self.bpp, &self.palette, None, chunk)
+            .expect("palette indices are checked in open")
+    }
+}
+
+impl Image for MmapImage {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> Pixel {
+        match self.modified.get(&y) {
+            Some(row) => row[x as usize],
+            None => self.decode(x, y),
+        }
+    }
+
+    fn has_alpha(&self) -> bool {
+        self.bpp == 32
+    }
+
+    fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
+        if !self.modified.contains_key(&y) {
+            let row = (0..self.width).map(|x| self.decode(x, y)).collect();
+            self.modified.insert(y, row);
+        }
+        &mut self.modified.get_mut(&y).unwrap()[x as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::ImageDataStream;
+    use crate::test_util::temp_path;
+    use std::fs;
+
+    #[test]
+    fn matches_in_memory_reads() {
+        let path = temp_path("mmap.bmp");
+        let mut stream = ImageDataStream::new(BMP::new(13, 9));
+        stream.embed_bytes(b"mapped").unwrap();
+        stream.into_inner().write(&path).unwrap();
+
+        let bmp = BMP::read(&path).unwrap();
+        let mapped = MmapImage::open(&path).unwrap();
+        assert_eq!((mapped.width(), mapped.height()), (13, 9));
+        assert!(bmp.pixels().eq(mapped.pixels()));
+
+        let stream = ImageDataStream::new(mapped);
+        assert_eq!(stream.extract_bytes().unwrap(), b"mapped");
+        drop(stream);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_decode_without_keeping_rows() {
+        let path = temp_path("mmap_sparse.bmp");
+        let bmp = BMP::new(6, 5);
+        bmp.write(&path).unwrap();
+
+        let mut mapped = MmapImage::open(&path).unwrap();
+        assert!(bmp.pixels().eq(mapped.pixels()));
+        assert!(mapped.modified.is_empty());
+
+        mapped.pixel_mut(2, 3).r ^= 1;
+        assert_eq!(mapped.modified.len(), 1);
+        assert_eq!(mapped.pixel(2, 3).r, bmp.pixel(2, 3).r ^ 1);
+        assert_eq!(mapped.pixel(3, 3), bmp.pixel(3, 3));
+        drop(mapped);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_os2_core_header() {
+        let path = temp_path("mmap_core.bmp");
+        let mut data = Vec::new();
+        data.extend_from_slice(&BMP::MAGIC.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&26u32.to_le_bytes());
+        data.extend_from_slice(&12u32.to_le_bytes());
+        for field in [2u16, 2, 1, 24] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&[3, 2, 1, 6, 5, 4, 0, 0]);
+        data.extend_from_slice(&[9, 8, 7, 12, 11, 10, 0, 0]);
+        fs::write(&path, &data).unwrap();
+
+        let bmp = BMP::from_bytes(&data).unwrap();
+        let mapped = MmapImage::open(&path).unwrap();
+        assert_eq!((mapped.width(), mapped.height()), (2, 2));
+        assert!(bmp.pixels().eq(mapped.pixels()));
+        drop(mapped);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unsupported_header_size() {
+        let path = temp_path("mmap_hdr20.bmp");
+        let mut data = BMP::new(2, 2).to_bytes().unwrap();
+        data[14..18].copy_from_slice(&20u32.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+
+        let err = MmapImage::open(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), "unsupported BMP info header size 20");
+    }
+}
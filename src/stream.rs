@@ -0,0 +1,2505 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::collections::HashMap;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use crate::archive;
+use crate::bits::{BitReader, BitWriter, WordPacker, WordUnpacker};
+use crate::bmp::BMP;
+use crate::cipher;
+use crate::error::{Result, StegError};
+use crate::header::Header;
+use crate::image::{check_same_size, Image, Pixel};
+use crate::rng::SplitMix64;
+
+/// Channel mask bits for `ImageDataStream::with_channels`.
+pub const CHANNEL_RED: u8 = 1 << 0;
+pub const CHANNEL_GREEN: u8 = 1 << 1;
+pub const CHANNEL_BLUE: u8 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChannelBits {
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+}
+
+impl ChannelBits {
+    pub const DEFAULT: Self = Self {
+        r: 3,
+        g: 2,
+        b: 2,
+        a: 0,
+    };
+
+    pub const MAX_ALPHA_BITS: u8 = 4;
+
+    pub const fn blue_only(b: u8) -> Self {
+        Self {
+            r: 0,
+            g: 0,
+            b,
+            a: 0,
+        }
+    }
+
+    pub const fn alpha_only(a: u8) -> Self {
+        Self {
+            r: 0,
+            g: 0,
+            b: 0,
+            a,
+        }
+    }
+
+    /// The default bits of the channels selected by `mask`, none in the rest.
+    pub const fn channels(mask: u8) -> Self {
+        const fn pick(mask: u8, channel: u8, bits: u8) -> u8 {
+            if mask & channel != 0 {
+                bits
+            } else {
+                0
+            }
+        }
+
+        Self {
+            r: pick(mask, CHANNEL_RED, Self::DEFAULT.r),
+            g: pick(mask, CHANNEL_GREEN, Self::DEFAULT.g),
+            b: pick(mask, CHANNEL_BLUE, Self::DEFAULT.b),
+            a: 0,
+        }
+    }
+
+    // Configs that leave a colour channel unused keep their header in the
+    // same channels as the data, so the unused ones are never touched.
+    pub fn is_masked(&self) -> bool {
+        self.r == 0 || self.g == 0 || self.b == 0
+    }
+
+    fn mask_of(&self) -> u8 {
+        let bit = |bits: u8, channel: u8| if bits > 0 { channel } else { 0 };
+        bit(self.r, CHANNEL_RED) | bit(self.g, CHANNEL_GREEN) | bit(self.b, CHANNEL_BLUE)
+    }
+
+    fn new(r: u8, g: u8, b: u8) -> Result<Self> {
+        for bits in [r, g, b] {
+            if !(1..=8).contains(&bits) {
+                return Err(StegError::InvalidArgument(format!(
+                    "bits per channel must be in 1..=8, got {}",
+                    bits
+                )));
+            }
+        }
+        if r + g + b > 8 {
+            return Err(StegError::InvalidArgument(format!(
+                "total bits per pixel must not exceed 8, got {}",
+                r + g + b
+            )));
+        }
+
+        Ok(Self { r, g, b, a: 0 })
+    }
+
+    const fn mask(bits: u8) -> u8 {
+        ((1u16 << bits) - 1) as u8
+    }
+
+    pub const fn word_size(&self) -> u8 {
+        self.r + self.g + self.b + self.a
+    }
+
+    fn read(&self, pixel: &Pixel) -> u8 {
+        let b_pos = self.a;
+        let g_pos = b_pos + self.b;
+        let r_pos = g_pos + self.g;
+
+        (pixel.r & Self::mask(self.r)) << r_pos
+            | (pixel.g & Self::mask(self.g)) << g_pos
+            | (pixel.b & Self::mask(self.b)) << b_pos
+            | (pixel.a & Self::mask(self.a))
+    }
+
+    fn write(&self, pixel: &mut Pixel, value: u8) {
+        let r_mask = Self::mask(self.r);
+        let g_mask = Self::mask(self.g);
+        let b_mask = Self::mask(self.b);
+        let a_mask = Self::mask(self.a);
+        let b_pos = self.a;
+        let g_pos = b_pos + self.b;
+        let r_pos = g_pos + self.g;
+
+        pixel.r = (pixel.r & !r_mask) | ((value >> r_pos) & r_mask);
+        pixel.g = (pixel.g & !g_mask) | ((value >> g_pos) & g_mask);
+        pixel.b = (pixel.b & !b_mask) | ((value >> b_pos) & b_mask);
+        pixel.a = (pixel.a & !a_mask) | (value & a_mask);
+    }
+
+    fn write_mode(&self, pixel: &mut Pixel, value: u8, mode: EmbedMode, addr: u64) {
+        match mode {
+            EmbedMode::Replace => self.write(pixel, value),
+            EmbedMode::MatchingLsb => {
+                self.write_matching(pixel, value, SplitMix64::new(addr).next_u64())
+            }
+        }
+    }
+
+    fn write_matching(&self, pixel: &mut Pixel, value: u8, noise: u64) {
+        let b_pos = self.a;
+        let g_pos = b_pos + self.b;
+        let r_pos = g_pos + self.g;
+
+        pixel.r = Self::match_channel(pixel.r, self.r, value >> r_pos, noise);
+        pixel.g = Self::match_channel(pixel.g, self.g, value >> g_pos, noise >> 1);
+        pixel.b = Self::match_channel(pixel.b, self.b, value >> b_pos, noise >> 2);
+        pixel.a = Self::match_channel(pixel.a, self.a, value, noise >> 3);
+    }
+
+    // Of the values holding `target` in their low bits, picks the one
+    // nearest `current`; for single bits that is a random step of +1 or -1
+    // rather than always flipping the LSB, which leaves pairs of histogram
+    // bins unequalised.
+    fn match_channel(current: u8, bits: u8, target: u8, noise: u64) -> u8 {
+        let mask = Self::mask(bits);
+        let replaced = (current & !mask) | (target & mask);
+        if replaced == current {
+            return current;
+        }
+
+        let step = 1i16 << bits;
+        let (current, replaced) = (current as i16, replaced as i16);
+        let other = if replaced > current {
+            replaced - step
+        } else {
+            replaced + step
+        };
+        if !(0..=255).contains(&other) {
+            return replaced as u8;
+        }
+
+        let (near, far) = ((replaced - current).abs(), (other - current).abs());
+        let pick = if near < far || (near == far && noise & 1 == 0) {
+            replaced
+        } else {
+            other
+        };
+        pick as u8
+    }
+
+    // Each field stores bits - 1, so an unused channel wraps around to 0b111;
+    // 8 bits in one channel would need the other two empty and is never valid.
+    // Alpha-only configs, which have no RGB bits, reuse the green field.
+    pub fn encode(&self) -> u64 {
+        let field = |bits: u8| (bits.wrapping_sub(1) & 0b111) as u64;
+        let g = if self.a > 0 { self.a } else { self.g };
+        field(self.r) | field(g) << 3 | field(self.b) << 6
+    }
+
+    pub fn decode(value: u64) -> Result<Self> {
+        let field = |shift: u64| (((value >> shift) & 0b111) as u8 + 1) & 0b111;
+        let (r, g, b) = (field(0), field(3), field(6));
+        if r == 0 && g == 0 && (1..=2).contains(&b) {
+            return Ok(Self::blue_only(b));
+        }
+        if r == 0 && b == 0 && (1..=Self::MAX_ALPHA_BITS).contains(&g) {
+            return Ok(Self::alpha_only(g));
+        }
+        let bits = Self { r, g, b, a: 0 };
+        if bits.word_size() > 0 && bits.is_masked() && bits == Self::channels(bits.mask_of()) {
+            return Ok(bits);
+        }
+
+        Self::new(r, g, b)
+            .map_err(|e| StegError::Corrupted(format!("corrupted stego header: {}", e)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    Zero,
+    Random(u64),
+}
+
+/// How payload bits are written into the channels. `MatchingLsb` moves a
+/// channel by the smallest amount that gives it the wanted low bits instead
+/// of overwriting them, which is harder to detect; both read back the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmbedMode {
+    #[default]
+    Replace,
+    MatchingLsb,
+}
+
+/// How a payload of a given size fits the carrier under the current config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FitReport {
+    pub capacity: u64,
+    pub requested: u64,
+    pub remaining: u64,
+}
+
+impl FitReport {
+    pub fn fits(&self) -> bool {
+        self.requested <= self.capacity
+    }
+
+    /// Share of the capacity the payload takes, in percent; over 100 when
+    /// it doesn't fit.
+    pub fn usage_percent(&self) -> f64 {
+        if self.capacity == 0 {
+            return if self.requested == 0 {
+                0.0
+            } else {
+                f64::INFINITY
+            };
+        }
+        self.requested as f64 * 100.0 / self.capacity as f64
+    }
+}
+
+pub struct ImageDataStream<T: Image> {
+    image: T,
+    bits: ChannelBits,
+    filename: Option<String>,
+    password: Option<String>,
+    auth_key: Option<String>,
+    compress: bool,
+    seed: Option<u64>,
+    noise: Option<u64>,
+    decoy: Option<f64>,
+    clear: bool,
+    start: u64,
+    mode: EmbedMode,
+    adaptive: bool,
+}
+
+/// A stream whose image backend is chosen at runtime.
+pub type DynImageDataStream = ImageDataStream<Box<dyn Image>>;
+
+/// Collects stream options so several can be set at once; `build` applies
+/// them to an image and reports the first invalid one.
+#[derive(Debug, Clone, Default)]
+pub struct ImageDataStreamBuilder {
+    bits: Option<(u8, u8, u8)>,
+    channels: Option<u8>,
+    filename: Option<String>,
+    password: Option<String>,
+    auth_key: Option<String>,
+    compress: bool,
+    seed: Option<u64>,
+    noise: Option<u64>,
+    decoy: Option<f64>,
+    clear: bool,
+    start: u64,
+    mode: EmbedMode,
+    adaptive: bool,
+}
+
+impl ImageDataStreamBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bits(mut self, r_bits: u8, g_bits: u8, b_bits: u8) -> Self {
+        self.bits = Some((r_bits, g_bits, b_bits));
+        self
+    }
+
+    pub fn channels(mut self, mask: u8) -> Self {
+        self.channels = Some(mask);
+        self
+    }
+
+    pub fn filename(mut self, filename: &str) -> Self {
+        self.filename = Some(filename.to_owned());
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_owned());
+        self
+    }
+
+    pub fn auth_key(mut self, key: &str) -> Self {
+        self.auth_key = Some(key.to_owned());
+        self
+    }
+
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn noise_fill(mut self, seed: u64) -> Self {
+        self.noise = Some(seed);
+        self
+    }
+
+    pub fn decoy(mut self, fraction: f64) -> Self {
+        self.decoy = Some(fraction);
+        self
+    }
+
+    pub fn clear_before_write(mut self, clear: bool) -> Self {
+        self.clear = clear;
+        self
+    }
+
+    pub fn data_offset(mut self, pixels: u64) -> Self {
+        self.start = pixels;
+        self
+    }
+
+    pub fn embed_mode(mut self, mode: EmbedMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    pub fn build<T: Image>(self, image: T) -> Result<ImageDataStream<T>> {
+        let mut stream = match (self.bits, self.channels) {
+            (Some(_), Some(_)) => {
+                return Err(StegError::InvalidArgument(
+                    "channel bits and channel mask can't both be set".into(),
+                ))
+            }
+            (Some((r, g, b)), None) => ImageDataStream::with_bits(image, r, g, b)?,
+            (None, Some(mask)) => ImageDataStream::with_channels(image, mask)?,
+            (None, None) => ImageDataStream::new(image),
+        };
+        if let Some(filename) = &self.filename {
+            stream.set_filename(filename)?;
+        }
+        if let Some(password) = &self.password {
+            stream.set_password(password);
+        }
+        if let Some(key) = &self.auth_key {
+            stream.set_auth_key(key);
+        }
+        stream.set_compression(self.compress);
+        if let Some(seed) = self.seed {
+            stream.set_seed(seed);
+        }
+        if let Some(noise) = self.noise {
+            stream.set_noise_fill(noise);
+        }
+        if let Some(fraction) = self.decoy {
+            stream.set_decoy(fraction)?;
+        }
+        stream.set_clear_before_write(self.clear);
+        stream.set_data_offset(self.start)?;
+        stream.set_embed_mode(self.mode);
+        stream.set_adaptive(self.adaptive);
+        Ok(stream)
+    }
+}
+
+impl<T: Image> ImageDataStream<T> {
+    pub const PROGRESS_INTERVAL: u64 = 4096;
+
+    /// Largest payload a compressed embedding may expand to on extraction.
+    pub const MAX_INFLATED_LEN: u64 = 1 << 28;
+
+    /// Bits hidden per pixel by the default channel layout, which is also
+    /// the layout of the header in multi-channel modes.
+    ///
+    /// ```
+    /// use lab2::{ImageDataStream, BMP};
+    ///
+    /// type Stream = ImageDataStream<BMP>;
+    /// assert_eq!(Stream::WORD_SIZE, 7);
+    ///
+    /// let capacity = (100 * 100 - Stream::HEADER_WORDS) * Stream::WORD_SIZE as u64 / 8;
+    /// assert_eq!(capacity, Stream::new(BMP::new(100, 100)).capacity());
+    /// ```
+    pub const WORD_SIZE: u8 = ChannelBits::DEFAULT.word_size();
+
+    /// Pixels taken by a header without a filename in the default layout;
+    /// the payload starts right after it.
+    pub const HEADER_WORDS: u64 = Header::min_words(Self::WORD_SIZE);
+
+    pub fn new(image: T) -> Self {
+        Self {
+            image,
+            bits: ChannelBits::DEFAULT,
+            filename: None,
+            password: None,
+            auth_key: None,
+            compress: false,
+            seed: None,
+            noise: None,
+            decoy: None,
+            clear: false,
+            start: 0,
+            mode: EmbedMode::Replace,
+            adaptive: false,
+        }
+    }
+
+    pub fn with_bits(image: T, r_bits: u8, g_bits: u8, b_bits: u8) -> Result<Self> {
+        let bits = ChannelBits::new(r_bits, g_bits, b_bits)?;
+        Ok(Self {
+            image,
+            bits,
+            filename: None,
+            password: None,
+            auth_key: None,
+            compress: false,
+            seed: None,
+            noise: None,
+            decoy: None,
+            clear: false,
+            start: 0,
+            mode: EmbedMode::Replace,
+            adaptive: false,
+        })
+    }
+
+    /// Hides data, header included, only in the channels selected by `mask`,
+    /// a combination of `CHANNEL_RED`, `CHANNEL_GREEN` and `CHANNEL_BLUE`,
+    /// each using its default number of bits. The others are left untouched.
+    pub fn with_channels(image: T, mask: u8) -> Result<Self> {
+        let all = CHANNEL_RED | CHANNEL_GREEN | CHANNEL_BLUE;
+        if mask == 0 || mask & !all != 0 {
+            return Err(StegError::InvalidArgument(format!(
+                "invalid channel mask {:#05b}",
+                mask
+            )));
+        }
+
+        let mut stream = Self::new(image);
+        stream.bits = ChannelBits::channels(mask);
+        Ok(stream)
+    }
+
+    /// Hides data, header included, only in the lowest one or two bits of
+    /// the blue channel, leaving red and green untouched.
+    pub fn blue_only(image: T, bits: u8) -> Result<Self> {
+        if !(1..=2).contains(&bits) {
+            return Err(StegError::InvalidArgument(format!(
+                "blue-only mode uses 1 or 2 bits, got {}",
+                bits
+            )));
+        }
+
+        let mut stream = Self::new(image);
+        stream.bits = ChannelBits::blue_only(bits);
+        Ok(stream)
+    }
+
+    /// Hides data, header included, only in the low bits of the alpha
+    /// channel. Fails for images without alpha, such as 24bpp BMPs.
+    pub fn alpha_only(image: T, bits: u8) -> Result<Self> {
+        if !(1..=ChannelBits::MAX_ALPHA_BITS).contains(&bits) {
+            return Err(StegError::InvalidArgument(format!(
+                "alpha mode uses 1 to {} bits, got {}",
+                ChannelBits::MAX_ALPHA_BITS,
+                bits
+            )));
+        }
+        if !image.has_alpha() {
+            return Err(StegError::InvalidArgument(
+                "alpha mode requires an image with an alpha channel".into(),
+            ));
+        }
+
+        let mut stream = Self::new(image);
+        stream.bits = ChannelBits::alpha_only(bits);
+        Ok(stream)
+    }
+
+    pub fn set_filename(&mut self, filename: &str) -> Result<()> {
+        if filename.len() > Header::MAX_FILENAME_LEN {
+            return Err(StegError::InvalidArgument(format!(
+                "filename of {} bytes exceeds limit {}",
+                filename.len(),
+                Header::MAX_FILENAME_LEN
+            )));
+        }
+
+        self.filename = Some(filename.to_owned());
+        Ok(())
+    }
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password = Some(password.to_owned());
+    }
+
+    /// Stores an HMAC of the payload keyed by `key`, and requires one that
+    /// matches on extraction, which fails with `AuthenticationFailed` if
+    /// the payload was changed.
+    pub fn set_auth_key(&mut self, key: &str) {
+        self.auth_key = Some(key.to_owned());
+    }
+
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// Fills every data word past the payload with noise from `seed`, so
+    /// there is no statistical edge where the payload ends.
+    pub fn set_noise_fill(&mut self, seed: u64) {
+        self.noise = Some(seed);
+    }
+
+    /// Pads the payload with random bytes until `fraction` of the capacity
+    /// is used, so LSB entropy doesn't drop right where the payload ends.
+    /// The header keeps the true length, so extraction skips the padding.
+    pub fn set_decoy(&mut self, fraction: f64) -> Result<()> {
+        if !(0.0..1.0).contains(&fraction) {
+            return Err(StegError::InvalidArgument(format!(
+                "decoy fraction must be in [0, 1), got {}",
+                fraction
+            )));
+        }
+
+        self.decoy = Some(fraction);
+        Ok(())
+    }
+
+    /// When enabled, embedding clears the whole carrier first so no bits of
+    /// an earlier, longer payload survive past the new one.
+    pub fn set_clear_before_write(&mut self, clear: bool) {
+        self.clear = clear;
+    }
+
+    pub fn set_embed_mode(&mut self, mode: EmbedMode) {
+        self.mode = mode;
+    }
+
+    /// Fills the most textured pixels first, where changes are hardest to
+    /// see. The order only depends on bits that embedding never touches, so
+    /// extraction rebuilds it; it can't be combined with a seed or with
+    /// `EmbedMode::MatchingLsb`, whose steps can carry into those bits.
+    pub fn set_adaptive(&mut self, adaptive: bool) {
+        self.adaptive = adaptive;
+    }
+
+    /// Leaves `pixels` untouched between the header and the payload, for
+    /// carriers whose top rows would show changes. The offset is stored in
+    /// the header, so extraction needs no configuration.
+    pub fn set_data_offset(&mut self, pixels: u64) -> Result<()> {
+        if pixels > Header::MAX_START {
+            return Err(StegError::InvalidArgument(format!(
+                "data offset {} exceeds limit {}",
+                pixels,
+                Header::MAX_START
+            )));
+        }
+
+        self.start = pixels;
+        Ok(())
+    }
+
+    // Addresses are u64 throughout, since carriers can hold more than
+    // u32::MAX pixels; only the coordinates, each below a u32 dimension,
+    // are narrowed.
+    fn coords(&self, addr: u64) -> (u32, u32) {
+        assert!(
+            addr < self.words(),
+            "pixel address {} out of bounds for {} pixels",
+            addr,
+            self.words()
+        );
+        let width = self.image.width() as u64;
+        ((addr % width) as u32, (addr / width) as u32)
+    }
+
+    fn pixel(&self, addr: u64) -> Pixel {
+        let (x, y) = self.coords(addr);
+        self.image.pixel(x, y)
+    }
+
+    fn pixel_mut(&mut self, addr: u64) -> &mut Pixel {
+        let (x, y) = self.coords(addr);
+        self.image.pixel_mut(x, y)
+    }
+
+    pub fn read_word(&self, addr: u64) -> u8 {
+        self.bits.read(&self.pixel(addr))
+    }
+
+    pub fn write_word(&mut self, addr: u64, value: u8) {
+        let bits = self.bits;
+        bits.write(self.pixel_mut(addr), value);
+    }
+
+    fn store_word(&mut self, bits: ChannelBits, addr: u64, value: u8) {
+        let mode = self.mode;
+        bits.write_mode(self.pixel_mut(addr), value, mode, addr);
+    }
+
+    fn words(&self) -> u64 {
+        self.image.width() as u64 * self.image.height() as u64
+    }
+
+    fn header(&self, bytes: u64) -> Header {
+        Header {
+            bytes,
+            bits: self.bits,
+            filename: self.filename.clone(),
+            crc: None,
+            flags: 0,
+            start: self.start,
+            salt: self.password.as_ref().map(|_| [0; cipher::SALT_LEN]),
+            mac: self.auth_key.as_ref().map(|_| [0; 32]),
+        }
+    }
+
+    // Header words use the default channel bits so a decoder can parse them
+    // before it knows how the payload was embedded. Masked carriers keep the
+    // header in their own channels, and are found by probing for the
+    // signature.
+    const HEADER_LAYOUTS: [ChannelBits; 12] = [
+        ChannelBits::DEFAULT,
+        ChannelBits::channels(CHANNEL_RED | CHANNEL_GREEN),
+        ChannelBits::channels(CHANNEL_RED | CHANNEL_BLUE),
+        ChannelBits::channels(CHANNEL_GREEN | CHANNEL_BLUE),
+        ChannelBits::channels(CHANNEL_RED),
+        ChannelBits::channels(CHANNEL_GREEN),
+        ChannelBits::blue_only(2),
+        ChannelBits::blue_only(1),
+        ChannelBits::alpha_only(4),
+        ChannelBits::alpha_only(3),
+        ChannelBits::alpha_only(2),
+        ChannelBits::alpha_only(1),
+    ];
+
+    fn header_bits(&self) -> ChannelBits {
+        if self.bits.is_masked() {
+            self.bits
+        } else {
+            ChannelBits::DEFAULT
+        }
+    }
+
+    fn header_word(&self, bits: ChannelBits, addr: &mut u64) -> Result<u8> {
+        if *addr >= self.words() {
+            return Err(StegError::Corrupted("stego header exceeds carrier".into()));
+        }
+
+        let word = bits.read(&self.pixel(*addr));
+        *addr += 1;
+        Ok(word)
+    }
+
+    fn signature_layout(&self) -> Option<ChannelBits> {
+        Self::HEADER_LAYOUTS.into_iter().find(|&bits| {
+            let mut addr: u64 = 0;
+            Header::has_signature(bits.word_size(), || self.header_word(bits, &mut addr))
+                .unwrap_or(false)
+        })
+    }
+
+    fn read_header(&self) -> Result<(Header, u64)> {
+        let bits = self.signature_layout().unwrap_or(ChannelBits::DEFAULT);
+        let mut addr: u64 = 0;
+        let mut header = Header::decode(bits.word_size(), || self.header_word(bits, &mut addr))?;
+        // Green-only and alpha-only configs encode alike; alpha payloads
+        // always keep their header in alpha.
+        if bits.a == 0 && header.bits.a > 0 {
+            header.bits = ChannelBits::channels(CHANNEL_GREEN);
+        }
+
+        Ok((header, addr))
+    }
+
+    fn write_header(&mut self, header: &Header) -> u64 {
+        let bits = self.header_bits();
+        let words = header.encode(bits.word_size());
+        for (addr, &word) in words.iter().enumerate() {
+            self.store_word(bits, addr as u64, word);
+        }
+
+        words.len() as u64
+    }
+
+    pub fn clear(&mut self) {
+        self.sanitize(Fill::Zero);
+    }
+
+    /// Overwrites every embeddable bit, under every header layout and the
+    /// data channel masks, destroying any hidden payload whatever its config.
+    pub fn sanitize(&mut self, fill: Fill) {
+        let mut rng = match fill {
+            Fill::Zero => None,
+            Fill::Random(seed) => Some(SplitMix64::new(seed)),
+        };
+        let mut masks = Self::HEADER_LAYOUTS.iter().chain([&self.bits]).fold(
+            Pixel {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0,
+            },
+            |masks, bits| Pixel {
+                r: masks.r | ChannelBits::mask(bits.r),
+                g: masks.g | ChannelBits::mask(bits.g),
+                b: masks.b | ChannelBits::mask(bits.b),
+                a: masks.a | ChannelBits::mask(bits.a),
+            },
+        );
+        if !self.image.has_alpha() {
+            masks.a = 0;
+        }
+
+        for addr in 0..self.words() {
+            let noise = rng.as_mut().map_or(0, |rng| rng.next_u64());
+            let pixel = self.pixel_mut(addr);
+            pixel.r = (pixel.r & !masks.r) | (noise as u8 & masks.r);
+            pixel.g = (pixel.g & !masks.g) | ((noise >> 8) as u8 & masks.g);
+            pixel.b = (pixel.b & !masks.b) | ((noise >> 16) as u8 & masks.b);
+            pixel.a = (pixel.a & !masks.a) | ((noise >> 24) as u8 & masks.a);
+        }
+    }
+
+    pub fn has_payload(&self) -> bool {
+        self.signature_layout().is_some()
+    }
+
+    /// Length of the embedded payload as stored, i.e. after compression,
+    /// read from the header alone; 0 when there is no payload.
+    pub fn payload_len(&self) -> u64 {
+        if !self.has_payload() {
+            return 0;
+        }
+        self.read_header().map_or(0, |(header, _)| header.bytes)
+    }
+
+    pub fn embedded_filename(&self) -> Result<Option<String>> {
+        Ok(self.read_header()?.0.filename)
+    }
+
+    fn header_words(&self) -> u64 {
+        self.header(0).encode(self.header_bits().word_size()).len() as u64
+    }
+
+    pub fn capacity(&self) -> u64 {
+        let data_start = self.header_words() + self.start;
+        (self.words().saturating_sub(data_start) * self.bits.word_size() as u64) / 8
+    }
+
+    /// Reports whether `payload_len` bytes, as stored after any
+    /// compression, fit the carrier and how much room they leave.
+    pub fn fits(&self, payload_len: u64) -> FitReport {
+        let capacity = self.capacity();
+        FitReport {
+            capacity,
+            requested: payload_len,
+            remaining: capacity.saturating_sub(payload_len),
+        }
+    }
+
+    /// Capacity in bytes of a `width`x`height` carrier embedded with the
+    /// given channel bits and no filename, without needing the image.
+    pub fn capacity_for(
+        width: u32,
+        height: u32,
+        r_bits: u8,
+        g_bits: u8,
+        b_bits: u8,
+    ) -> Result<u64> {
+        let bits = ChannelBits::new(r_bits, g_bits, b_bits)?;
+        let header = Header {
+            bytes: 0,
+            bits,
+            filename: None,
+            crc: None,
+            flags: 0,
+            start: 0,
+            salt: None,
+            mac: None,
+        };
+
+        let words = width as u64 * height as u64;
+        let data_start = header.encode(ChannelBits::DEFAULT.word_size()).len() as u64;
+        Ok((words.saturating_sub(data_start) * bits.word_size() as u64) / 8)
+    }
+
+    fn check_carrier(&self, needed: u64) -> Result<()> {
+        if self.words() < needed {
+            return Err(StegError::CarrierTooSmall {
+                pixels: self.words(),
+                needed,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_capacity(&self, bytes: u64) -> Result<()> {
+        self.check_carrier(self.header_words())?;
+        if bytes > Header::MAX_LENGTH {
+            return Err(StegError::LengthOverflow {
+                bytes,
+                max: Header::MAX_LENGTH,
+            });
+        }
+
+        let cap = self.capacity();
+        if bytes > cap {
+            return Err(StegError::PayloadTooLarge {
+                bytes,
+                capacity: cap,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Gradient towards the right and lower neighbours over the bits no
+    // header or data layout can change.
+    fn texture(&self, addr: u64, masks: &Pixel) -> u32 {
+        let high = |p: Pixel| {
+            [
+                p.r & !masks.r,
+                p.g & !masks.g,
+                p.b & !masks.b,
+                p.a & !masks.a,
+            ]
+        };
+        let width = self.image.width() as u64;
+        let here = high(self.pixel(addr));
+
+        let mut total = 0;
+        for (neighbour, exists) in [
+            (addr + 1, !(addr + 1).is_multiple_of(width)),
+            (addr + width, addr + width < self.words()),
+        ] {
+            if exists {
+                let there = high(self.pixel(neighbour));
+                total += here
+                    .iter()
+                    .zip(&there)
+                    .map(|(&a, &b)| a.abs_diff(b) as u32)
+                    .sum::<u32>();
+            }
+        }
+        total
+    }
+
+    fn data_addresses(
+        &self,
+        data_start: u64,
+        count: u64,
+        seed: Option<u64>,
+        adaptive: Option<ChannelBits>,
+    ) -> Box<dyn Iterator<Item = u64>> {
+        let words = self.words();
+        // Only the adaptive order needs every candidate address; the plain
+        // layout is a range and the shuffle is generated as it is consumed,
+        // so small payloads in huge carriers stay cheap.
+        if adaptive.is_none() {
+            let Some(seed) = seed else {
+                let start = data_start.min(words);
+                return Box::new(start..data_start.saturating_add(count).min(words));
+            };
+
+            // A partial Fisher-Yates shuffle of data_start..words, where the
+            // map holds only the slots a swap has moved away from identity.
+            let len = words.saturating_sub(data_start);
+            let mut rng = SplitMix64::new(seed);
+            let mut swapped: HashMap<u64, u64> = HashMap::new();
+            return Box::new((0..count.min(len)).map(move |i| {
+                let j = i + rng.below(len - i);
+                let picked = swapped.get(&j).copied().unwrap_or(j);
+                let displaced = swapped.remove(&i).unwrap_or(i);
+                if j != i {
+                    swapped.insert(j, displaced);
+                }
+                data_start + picked
+            }));
+        }
+
+        let mut addresses: Vec<u64> = (data_start..words).collect();
+        if let Some(data_bits) = adaptive {
+            let bits = ChannelBits {
+                r: data_bits.r.max(ChannelBits::DEFAULT.r),
+                g: data_bits.g.max(ChannelBits::DEFAULT.g),
+                b: data_bits.b.max(ChannelBits::DEFAULT.b),
+                a: data_bits.a,
+            };
+            let masks = Pixel {
+                r: ChannelBits::mask(bits.r),
+                g: ChannelBits::mask(bits.g),
+                b: ChannelBits::mask(bits.b),
+                a: ChannelBits::mask(bits.a),
+            };
+            // Stable, so equally textured pixels stay in address order.
+            addresses.sort_by_cached_key(|&addr| std::cmp::Reverse(self.texture(addr, &masks)));
+        }
+        if let Some(seed) = seed {
+            let mut rng = SplitMix64::new(seed);
+            for i in 0..count.min(addresses.len() as u64) {
+                let j = i + rng.below(addresses.len() as u64 - i);
+                addresses.swap(i as usize, j as usize);
+            }
+        }
+
+        addresses.truncate(count as usize);
+        Box::new(addresses.into_iter())
+    }
+
+    pub fn embed_bytes(&mut self, data: &[u8]) -> Result<()> {
+        for (addr, word) in self.prepare_embed(data, 0)? {
+            self.store_word(self.bits, addr, word);
+        }
+
+        Ok(())
+    }
+
+    pub fn embed_text(&mut self, msg: &str) -> Result<()> {
+        self.embed_bytes(msg.as_bytes())
+    }
+
+    /// Embeds several files as one payload, prefixed by a table of their
+    /// names, offsets and lengths.
+    pub fn embed_files(&mut self, files: &[&Path]) -> Result<()> {
+        let mut entries = Vec::with_capacity(files.len());
+        for path in files {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    StegError::InvalidArgument(format!("{} has no UTF-8 file name", path.display()))
+                })?;
+            entries.push((name.to_owned(), std::fs::read(path)?));
+        }
+
+        let data = archive::pack(&entries)?;
+        for (addr, word) in self.prepare_embed(&data, Header::ARCHIVE)? {
+            self.store_word(self.bits, addr, word);
+        }
+
+        Ok(())
+    }
+
+    pub fn extract_files(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let (header, data) = self.extract()?;
+        if header.flags & Header::ARCHIVE == 0 {
+            return Err(StegError::NotArchive);
+        }
+
+        archive::unpack(&data)
+    }
+
+    /// Writes the header and returns where each payload word goes, leaving
+    /// the data words themselves to the caller.
+    fn prepare_embed(&mut self, data: &[u8], mut flags: u8) -> Result<Vec<(u64, u8)>> {
+        let crc = crc32fast::hash(data);
+
+        let mut data = if self.compress {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(data)?;
+            flags |= Header::COMPRESSED;
+            encoder.finish()?
+        } else {
+            data.to_vec()
+        };
+
+        self.check_capacity(data.len() as u64)?;
+
+        let mut header = self.header(data.len() as u64);
+        header.crc = Some(crc);
+        header.flags = flags;
+
+        // A CRC of the plaintext would let anyone check password guesses
+        // offline, so encrypted payloads are checksummed after encryption.
+        if let Some(password) = &self.password {
+            let salt = cipher::random_salt()?;
+            cipher::apply_keystream(password, Some(&salt), &mut data);
+            header.crc = Some(crc32fast::hash(&data));
+            header.salt = Some(salt);
+            header.flags |= Header::ENCRYPTED;
+        }
+
+        if let Some(key) = &self.auth_key {
+            header.mac = Some(cipher::hmac(key, &data));
+            header.flags |= Header::AUTHENTICATED;
+        }
+
+        if self.seed.is_some() {
+            header.flags |= Header::SHUFFLED;
+        }
+
+        if self.adaptive {
+            if self.seed.is_some() || self.mode == EmbedMode::MatchingLsb {
+                return Err(StegError::InvalidArgument(
+                    "adaptive ordering can't be combined with a seed or LSB matching".into(),
+                ));
+            }
+            header.flags |= Header::ADAPTIVE;
+        }
+
+        if self.clear {
+            self.clear();
+        }
+        let data_start = self.write_header(&header) + self.start;
+
+        if let Some(fraction) = self.decoy {
+            let target = (self.capacity() as f64 * fraction) as usize;
+            data.extend(cipher::random_bytes(target.saturating_sub(data.len()))?);
+        }
+
+        let mut packer = WordPacker::new(self.bits.word_size());
+        for &byte in &data {
+            packer.push(byte as u64, 8);
+        }
+        let words = packer.into_words();
+        let count = match self.noise {
+            Some(_) => self.words().saturating_sub(data_start),
+            None => words.len() as u64,
+        };
+        let adaptive = self.adaptive.then_some(self.bits);
+        let mut addresses = self.data_addresses(data_start, count, self.seed, adaptive);
+        // Words go first in the zip so no address is dropped when they run out.
+        let mut placements: Vec<(u64, u8)> = words
+            .into_iter()
+            .zip(addresses.by_ref())
+            .map(|(word, addr)| (addr, word))
+            .collect();
+
+        if let Some(noise) = self.noise {
+            let mut rng = SplitMix64::new(noise);
+            for addr in addresses {
+                placements.push((addr, rng.next_u64() as u8));
+            }
+        }
+        Ok(placements)
+    }
+
+    // Untrusted carriers can hold a DEFLATE bomb, so output is capped.
+    fn inflate(data: &[u8], limit: u64) -> Result<Vec<u8>> {
+        let mut inflated = Vec::new();
+        DeflateDecoder::new(data)
+            .take(limit + 1)
+            .read_to_end(&mut inflated)
+            .map_err(|_| {
+                StegError::Corrupted(
+                    "payload decompression failed: corrupted or not a stego image".into(),
+                )
+            })?;
+        if inflated.len() as u64 > limit {
+            return Err(StegError::Corrupted(format!(
+                "decompressed payload exceeds limit of {} bytes",
+                limit
+            )));
+        }
+
+        Ok(inflated)
+    }
+
+    fn extract(&self) -> Result<(Header, Vec<u8>)> {
+        self.extract_with(|addresses, config| {
+            addresses
+                .iter()
+                .map(|&addr| config.read(&self.pixel(addr)))
+                .collect()
+        })
+    }
+
+    fn extract_with(
+        &self,
+        read_words: impl FnOnce(&[u64], ChannelBits) -> Vec<u8>,
+    ) -> Result<(Header, Vec<u8>)> {
+        self.check_carrier(Self::HEADER_WORDS)?;
+        let (header, header_words) = self.read_header()?;
+        let data_start = header_words + header.start;
+        let config = header.bits;
+        let words = self.words();
+
+        let available = (words.saturating_sub(data_start) * config.word_size() as u64) / 8;
+        if header.bytes > available {
+            return Err(StegError::Corrupted(format!(
+                "embedded length {} exceeds carrier capacity {}",
+                header.bytes, available
+            )));
+        }
+
+        let seed = if header.flags & Header::SHUFFLED != 0 {
+            let seed = self.seed.ok_or(StegError::SeedRequired)?;
+            Some(seed)
+        } else {
+            None
+        };
+
+        let count = (header.bytes * 8).div_ceil(config.word_size() as u64);
+        let adaptive = (header.flags & Header::ADAPTIVE != 0).then_some(config);
+        let addresses: Vec<u64> = self
+            .data_addresses(data_start, count, seed, adaptive)
+            .collect();
+        let mut words = read_words(&addresses, config).into_iter();
+        let mut unpacker = WordUnpacker::new(config.word_size(), || Ok(words.next().unwrap()));
+
+        let mut data = Vec::with_capacity(header.bytes as usize);
+        for _ in 0..header.bytes {
+            data.push(unpacker.take(8)? as u8);
+        }
+
+        match (&header.mac, &self.auth_key) {
+            (Some(mac), Some(key)) => {
+                if !cipher::verify_hmac(key, &data, mac) {
+                    return Err(StegError::AuthenticationFailed);
+                }
+            }
+            (Some(_), None) => return Err(StegError::AuthKeyRequired),
+            (None, Some(_)) => return Err(StegError::AuthenticationFailed),
+            (None, None) => {}
+        }
+
+        let check_crc = |data: &[u8]| match header.crc {
+            Some(crc) if crc32fast::hash(data) != crc => Err(StegError::ChecksumMismatch),
+            _ => Ok(()),
+        };
+        if header.salt.is_some() {
+            check_crc(&data)?;
+        }
+
+        if header.flags & Header::ENCRYPTED != 0 {
+            let password = self
+                .password
+                .as_deref()
+                .ok_or(StegError::PasswordRequired)?;
+            cipher::apply_keystream(password, header.salt.as_ref(), &mut data);
+        }
+
+        if header.flags & Header::COMPRESSED != 0 {
+            data = Self::inflate(&data, Self::MAX_INFLATED_LEN)?;
+        }
+
+        if header.salt.is_none() {
+            check_crc(&data)?;
+        }
+
+        Ok((header, data))
+    }
+
+    pub fn extract_bytes(&self) -> Result<Vec<u8>> {
+        Ok(self.extract()?.1)
+    }
+
+    /// Re-extracts the payload and checks it equals `original`, to catch an
+    /// unrecoverable embedding before the carrier is saved.
+    pub fn verify(&self, original: &[u8]) -> Result<()> {
+        let data = self.extract_bytes()?;
+        if let Some(pos) = data.iter().zip(original).position(|(a, b)| a != b) {
+            return Err(StegError::Corrupted(format!(
+                "verification failed: payload differs at byte {}",
+                pos
+            )));
+        }
+        if data.len() != original.len() {
+            return Err(StegError::Corrupted(format!(
+                "verification failed: extracted {} bytes, expected {}",
+                data.len(),
+                original.len()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn extract_text(&self) -> Result<String> {
+        String::from_utf8(self.extract_bytes()?)
+            .map_err(|_| StegError::Corrupted("payload is not valid UTF-8 text".into()))
+    }
+
+    pub fn read_stream(&self, output: &mut BitWriter<impl Write>) -> Result<Option<String>> {
+        self.read_stream_with_progress(output, &mut |_, _| {})
+    }
+
+    /// Like `read_stream`, calling `progress(done, total)` in bytes every
+    /// `PROGRESS_INTERVAL` bytes and once at the end.
+    pub fn read_stream_with_progress(
+        &self,
+        output: &mut BitWriter<impl Write>,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<Option<String>> {
+        let (header, data) = self.extract()?;
+        let total = data.len() as u64;
+        for (done, byte) in data.into_iter().enumerate() {
+            output.write_bits(byte, 8)?;
+            if done > 0 && (done as u64).is_multiple_of(Self::PROGRESS_INTERVAL) {
+                progress(done as u64, total);
+            }
+        }
+        progress(total, total);
+
+        Ok(header.filename)
+    }
+
+    pub fn write_stream(&mut self, input: &mut BitReader<impl Read + Seek>) -> Result<()> {
+        self.write_stream_with_progress(input, &mut |_, _| {})
+    }
+
+    /// Like `write_stream`, calling `progress(done, total)` in bytes every
+    /// `PROGRESS_INTERVAL` bytes and once at the end.
+    pub fn write_stream_with_progress(
+        &mut self,
+        input: &mut BitReader<impl Read + Seek>,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<()> {
+        // Compressed payloads are checked against the capacity once deflated,
+        // so until then the declared size is only held to what extraction
+        // would inflate.
+        let total = input.size;
+        if self.compress {
+            if total > Self::MAX_INFLATED_LEN {
+                return Err(StegError::LengthOverflow {
+                    bytes: total,
+                    max: Self::MAX_INFLATED_LEN,
+                });
+            }
+        } else {
+            self.check_capacity(total)?;
+        }
+
+        let mut data = Vec::with_capacity(total as usize);
+        for done in 0..total {
+            data.push(input.read_bits(8)?);
+            if done > 0 && done.is_multiple_of(Self::PROGRESS_INTERVAL) {
+                progress(done, total);
+            }
+        }
+
+        self.embed_bytes(&data)?;
+        progress(total, total);
+        Ok(())
+    }
+
+    /// Colour of header pixels in `capacity_heatmap`.
+    pub const HEATMAP_HEADER: Pixel = Pixel {
+        r: 0xFF,
+        g: 0,
+        b: 0,
+        a: 0xFF,
+    };
+
+    /// Colour of pixels no payload word lands in.
+    pub const HEATMAP_UNUSED: Pixel = Pixel {
+        r: 0,
+        g: 0,
+        b: 0,
+        a: 0xFF,
+    };
+
+    /// Renders the carrier layout: header pixels red, unused pixels black
+    /// and data pixels green, brighter the more payload bits each holds.
+    /// An embedded payload is mapped from its header; on a clean carrier the
+    /// map shows where a payload filling it would go under the current
+    /// config. Shuffled payloads without the seed mark every candidate.
+    pub fn capacity_heatmap(&self) -> BMP {
+        let words = self.words();
+        let (header_words, addresses, bits) = match self.read_header() {
+            Ok((header, header_words)) if self.has_payload() => {
+                let config = header.bits;
+                let data_start = header_words + header.start;
+                let shuffled = header.flags & Header::SHUFFLED != 0;
+                let count = match (shuffled, self.seed) {
+                    (true, None) => words,
+                    _ => (header.bytes.saturating_mul(8)).div_ceil(config.word_size() as u64),
+                };
+                let seed = self.seed.filter(|_| shuffled);
+                let adaptive = (header.flags & Header::ADAPTIVE != 0).then_some(config);
+                let addresses = self.data_addresses(data_start, count, seed, adaptive);
+                (header_words, addresses, config)
+            }
+            _ => {
+                let header_words = self.header_words().min(words);
+                let addresses: Box<dyn Iterator<Item = u64>> =
+                    Box::new(header_words + self.start..words);
+                (header_words, addresses, self.bits)
+            }
+        };
+
+        let mut map = BMP::filled(
+            self.image.width(),
+            self.image.height(),
+            Self::HEATMAP_UNUSED,
+        );
+        let data = Pixel {
+            g: (0xFF * bits.word_size() as u16 / 8) as u8,
+            ..Self::HEATMAP_UNUSED
+        };
+        let width = self.image.width() as u64;
+        let mut paint = |addr: u64, color: Pixel| {
+            *map.pixel_mut((addr % width) as u32, (addr / width) as u32) = color;
+        };
+        for addr in 0..header_words {
+            paint(addr, Self::HEATMAP_HEADER);
+        }
+        for addr in addresses {
+            paint(addr, data);
+        }
+        map
+    }
+
+    /// Counts how often each word value occurs across the carrier, for
+    /// chi-square style steganalysis.
+    pub fn lsb_histogram(&self) -> Vec<u64> {
+        let mut histogram = vec![0u64; 1 << self.bits.word_size()];
+        for (_, _, pixel) in self.image.pixels() {
+            histogram[self.bits.read(&pixel) as usize] += 1;
+        }
+        histogram
+    }
+
+    /// Mean absolute difference per colour channel between the carrier and
+    /// `original`, which must have the same dimensions.
+    pub fn mean_absolute_change(&self, original: &T) -> Result<f64> {
+        check_same_size(&self.image, original)?;
+
+        let total: u64 = self
+            .image
+            .pixels()
+            .map(|(x, y, pixel)| {
+                let before = original.pixel(x, y);
+                (pixel.r.abs_diff(before.r) as u64)
+                    + (pixel.g.abs_diff(before.g) as u64)
+                    + (pixel.b.abs_diff(before.b) as u64)
+            })
+            .sum();
+        Ok(total as f64 / (self.words() * 3).max(1) as f64)
+    }
+
+    pub fn image(&self) -> &T {
+        &self.image
+    }
+
+    /// Direct access to the carrier; pixels changed here change what is
+    /// extracted.
+    pub fn image_mut(&mut self) -> &mut T {
+        &mut self.image
+    }
+
+    pub fn into_inner(self) -> T {
+        self.image
+    }
+}
+
+#[cfg(feature = "rayon")]
+const PAR_CHUNK_PIXELS: usize = 1 << 14;
+
+#[cfg(feature = "rayon")]
+impl<T: Image + Sync> ImageDataStream<T> {
+    /// Same as `embed_bytes`, but writes the payload in parallel. Images
+    /// without contiguous pixel storage only compute the new pixels in
+    /// parallel and write them back one by one.
+    pub fn par_embed_bytes(&mut self, data: &[u8]) -> Result<()> {
+        use rayon::prelude::*;
+
+        let mut placements = self.prepare_embed(data, 0)?;
+        let (bits, mode) = (self.bits, self.mode);
+
+        // Addresses are row-major indices, so sorted placements split
+        // cleanly along the same boundaries as the pixel chunks.
+        if let Some(pixels) = self.image.contiguous_pixels_mut() {
+            placements.par_sort_unstable_by_key(|&(addr, _)| addr);
+            pixels
+                .par_chunks_mut(PAR_CHUNK_PIXELS)
+                .enumerate()
+                .for_each(|(i, chunk)| {
+                    let start = (i * PAR_CHUNK_PIXELS) as u64;
+                    let end = start + chunk.len() as u64;
+                    let lo = placements.partition_point(|&(addr, _)| addr < start);
+                    let hi = placements.partition_point(|&(addr, _)| addr < end);
+                    for &(addr, word) in &placements[lo..hi] {
+                        bits.write_mode(&mut chunk[(addr - start) as usize], word, mode, addr);
+                    }
+                });
+            return Ok(());
+        }
+
+        let this = &*self;
+        let pixels: Vec<(u64, Pixel)> = placements
+            .par_iter()
+            .map(|&(addr, word)| {
+                let mut pixel = this.pixel(addr);
+                bits.write_mode(&mut pixel, word, mode, addr);
+                (addr, pixel)
+            })
+            .collect();
+
+        for (addr, pixel) in pixels {
+            *self.pixel_mut(addr) = pixel;
+        }
+        Ok(())
+    }
+
+    /// Same as `extract_bytes`, but reads the payload words in parallel.
+    pub fn par_extract_bytes(&self) -> Result<Vec<u8>> {
+        use rayon::prelude::*;
+
+        let (_, data) = self.extract_with(|addresses, config| {
+            addresses
+                .par_iter()
+                .map(|&addr| config.read(&self.pixel(addr)))
+                .collect()
+        })?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bits::{FileBitReader, FileBitWriter, MemBitReader, MemBitWriter};
+    use crate::bmp::BMP;
+    use crate::test_util::{temp_path, TestImage};
+    use std::fs;
+
+    fn embed_and_extract(
+        stream: &mut ImageDataStream<TestImage>,
+        name: &str,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let input_path = temp_path(&format!("{}.in", name));
+        let output_path = temp_path(&format!("{}.out", name));
+        fs::write(&input_path, payload).unwrap();
+
+        let mut input = FileBitReader::open(&input_path).unwrap();
+        stream.write_stream(&mut input).unwrap();
+
+        let mut output = FileBitWriter::open(&output_path).unwrap();
+        stream.read_stream(&mut output).unwrap();
+        drop(output);
+
+        let extracted = fs::read(&output_path).unwrap();
+        fs::remove_file(&input_path).unwrap();
+        fs::remove_file(&output_path).unwrap();
+        extracted
+    }
+
+    #[test]
+    fn words_address_non_square_image() {
+        let mut stream = ImageDataStream::new(TestImage::new(7, 3));
+        for addr in 0..21 {
+            stream.write_word(addr, addr as u8 * 5 % 128);
+        }
+        for addr in 0..21 {
+            assert_eq!(stream.read_word(addr), addr as u8 * 5 % 128);
+        }
+    }
+
+    // Claims huge dimensions but only stores the pixels that are written.
+    struct SparseImage {
+        width: u32,
+        height: u32,
+        blank: Pixel,
+        pixels: std::collections::HashMap<(u32, u32), Pixel>,
+    }
+
+    impl Image for SparseImage {
+        fn width(&self) -> u32 {
+            self.width
+        }
+
+        fn height(&self) -> u32 {
+            self.height
+        }
+
+        fn pixel(&self, x: u32, y: u32) -> Pixel {
+            *self.pixels.get(&(x, y)).unwrap_or(&self.blank)
+        }
+
+        fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
+            let blank = self.blank;
+            self.pixels.entry((x, y)).or_insert(blank)
+        }
+    }
+
+    #[test]
+    fn addresses_past_u32_max_do_not_wrap() {
+        let image = SparseImage {
+            width: 1 << 17,
+            height: 1 << 16,
+            blank: Pixel {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0,
+            },
+            pixels: Default::default(),
+        };
+        let mut stream = ImageDataStream::new(image);
+        assert!(stream.capacity() > u32::MAX as u64);
+
+        let addr = u32::MAX as u64 + 10;
+        stream.write_word(addr, 0x5A);
+        assert_eq!(stream.read_word(addr), 0x5A);
+        assert_eq!(stream.read_word(addr - (1 << 32)), 0);
+
+        let image = stream.into_inner();
+        let x = (addr % (1 << 17)) as u32;
+        let y = (addr >> 17) as u32;
+        assert_eq!(image.pixels.keys().collect::<Vec<_>>(), [&(x, y)]);
+    }
+
+    #[test]
+    fn small_payload_in_huge_carrier_touches_only_its_pixels() {
+        let image = SparseImage {
+            width: 1 << 16,
+            height: 1 << 16,
+            blank: Pixel {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0,
+            },
+            pixels: Default::default(),
+        };
+        let mut stream = ImageDataStream::new(image);
+        stream.embed_bytes(b"tiny").unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"tiny");
+        assert!(stream.into_inner().pixels.len() < 64);
+    }
+
+    #[test]
+    fn seeded_payload_in_huge_carrier_touches_only_its_pixels() {
+        let image = SparseImage {
+            width: 1 << 16,
+            height: 1 << 16,
+            blank: Pixel {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0,
+            },
+            pixels: Default::default(),
+        };
+        let mut stream = ImageDataStream::new(image);
+        stream.set_seed(11);
+        stream.embed_bytes(b"tiny").unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"tiny");
+        assert!(stream.into_inner().pixels.len() < 64);
+    }
+
+    #[test]
+    fn seeded_addresses_match_a_full_shuffle() {
+        let stream = ImageDataStream::new(TestImage::new(9, 7));
+        let (data_start, count) = (5, 40);
+
+        let mut expected: Vec<u64> = (data_start..stream.words()).collect();
+        let mut rng = SplitMix64::new(3);
+        for i in 0..count as usize {
+            let j = i + rng.below((expected.len() - i) as u64) as usize;
+            expected.swap(i, j);
+        }
+        expected.truncate(count as usize);
+
+        let addresses: Vec<u64> = stream
+            .data_addresses(data_start, count, Some(3), None)
+            .collect();
+        assert_eq!(addresses, expected);
+    }
+
+    #[test]
+    fn round_trip_non_square_image() {
+        let mut stream = ImageDataStream::new(TestImage::new(17, 5));
+        let payload: Vec<u8> = (0..stream.capacity() as u8)
+            .map(|i| 0xA5 ^ i.wrapping_mul(37))
+            .collect();
+        assert_eq!(
+            embed_and_extract(&mut stream, "non_square", &payload),
+            payload
+        );
+    }
+
+    #[test]
+    fn write_stream_rejects_oversized_payload() {
+        let mut stream = ImageDataStream::new(TestImage::new(8, 4));
+        let cap = stream.capacity();
+
+        let path = temp_path("oversized.in");
+        fs::write(&path, vec![0u8; cap as usize + 1]).unwrap();
+        let mut input = FileBitReader::open(&path).unwrap();
+        let err = stream.write_stream(&mut input).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("payload of {} bytes exceeds capacity {}", cap + 1, cap)
+        );
+    }
+
+    #[test]
+    fn round_trip_one_bit_per_channel() {
+        let payload = b"stealthy";
+        let mut stream = ImageDataStream::with_bits(TestImage::new(8, 8), 1, 1, 1).unwrap();
+        assert_eq!(embed_and_extract(&mut stream, "bits111", payload), payload);
+    }
+
+    #[test]
+    fn round_trip_eight_bits_per_pixel() {
+        let mut stream = ImageDataStream::with_bits(TestImage::new(33, 8), 4, 2, 2).unwrap();
+        let payload: Vec<u8> = (0..stream.capacity() as u8).collect();
+        assert_eq!(embed_and_extract(&mut stream, "bits422", &payload), payload);
+    }
+
+    #[test]
+    fn extraction_uses_config_from_header() {
+        let payload = b"self-describing";
+        let input_path = temp_path("self_describing.in");
+        let output_path = temp_path("self_describing.out");
+        fs::write(&input_path, payload).unwrap();
+
+        let mut stream = ImageDataStream::with_bits(TestImage::new(8, 8), 4, 2, 2).unwrap();
+        let mut input = FileBitReader::open(&input_path).unwrap();
+        stream.write_stream(&mut input).unwrap();
+
+        let stream = ImageDataStream::new(stream.into_inner());
+        let mut output = FileBitWriter::open(&output_path).unwrap();
+        stream.read_stream(&mut output).unwrap();
+        drop(output);
+
+        let extracted = fs::read(&output_path).unwrap();
+        fs::remove_file(&input_path).unwrap();
+        fs::remove_file(&output_path).unwrap();
+        assert_eq!(extracted, payload);
+    }
+
+    #[test]
+    fn round_trip_preserves_filename() {
+        let payload = b"%PDF-1.4";
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        stream.set_filename("secret.pdf").unwrap();
+        assert_eq!(embed_and_extract(&mut stream, "filename", payload), payload);
+
+        let stream = ImageDataStream::new(stream.into_inner());
+        assert_eq!(
+            stream.embedded_filename().unwrap().as_deref(),
+            Some("secret.pdf")
+        );
+    }
+
+    #[test]
+    fn read_stream_detects_flipped_pixel() {
+        let payload = [0x5Au8; 20];
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        assert_eq!(
+            embed_and_extract(&mut stream, "crc_clean", &payload),
+            payload
+        );
+
+        let mut image = stream.into_inner();
+        image.pixel_mut(4, 3).b ^= 1;
+        let stream = ImageDataStream::new(image);
+
+        let path = temp_path("crc_flipped.out");
+        let mut output = FileBitWriter::open(&path).unwrap();
+        let err = stream.read_stream(&mut output).unwrap_err();
+        drop(output);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            "payload checksum mismatch: corrupted or not a stego image"
+        );
+    }
+
+    #[test]
+    fn payload_len_reads_header_length() {
+        let mut stream = ImageDataStream::new(TestImage::new(64, 64));
+        assert_eq!(stream.payload_len(), 0);
+        stream.embed_bytes(&[0xa5; 1234]).unwrap();
+        assert_eq!(stream.payload_len(), 1234);
+    }
+
+    #[test]
+    fn has_payload_detects_signature() {
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        assert!(!stream.has_payload());
+
+        embed_and_extract(&mut stream, "signature", b"marked");
+        assert!(stream.has_payload());
+    }
+
+    #[test]
+    fn password_protects_payload() {
+        let payload = b"launch codes";
+        let mut stream = ImageDataStream::new(TestImage::new(12, 12));
+        stream.set_password("hunter2");
+        assert_eq!(embed_and_extract(&mut stream, "password", payload), payload);
+
+        // The checksum covers the ciphertext, so a wrong password isn't
+        // reported, only garbles the payload.
+        let mut stream = ImageDataStream::new(stream.into_inner());
+        stream.set_password("hunter3");
+        assert_ne!(stream.extract_bytes().unwrap(), payload);
+    }
+
+    #[test]
+    fn each_encrypted_embedding_gets_its_own_keystream() {
+        let payload = [0u8; 32];
+        let embedded = || {
+            let mut stream = ImageDataStream::new(TestImage::new(32, 32));
+            stream.set_password("hunter2");
+            stream.embed_bytes(&payload).unwrap();
+            let (header, header_end) = stream.read_header().unwrap();
+            assert!(header.salt.is_some());
+            let data_start = header_end + header.start;
+            let count = (header.bytes * 8).div_ceil(header.bits.word_size() as u64);
+            let words: Vec<u8> = (data_start..data_start + count)
+                .map(|addr| stream.read_word(addr))
+                .collect();
+            assert_eq!(stream.extract_bytes().unwrap(), payload);
+            words
+        };
+        assert_ne!(embedded(), embedded());
+    }
+
+    #[test]
+    fn compression_fits_larger_payload() {
+        let payload: Vec<u8> = b"all work and no play makes jack a dull boy\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(10 * 1024)
+            .collect();
+        let mut stream = ImageDataStream::new(TestImage::new(40, 40));
+        assert!(stream.capacity() < payload.len() as u64);
+
+        stream.set_compression(true);
+        assert_eq!(
+            embed_and_extract(&mut stream, "compressed", &payload),
+            payload
+        );
+    }
+
+    #[test]
+    fn seed_spreads_payload_across_carrier() {
+        let payload = [0u8; 16];
+        let mut stream = ImageDataStream::new(TestImage::new(32, 32));
+        stream.set_seed(0xC0FFEE);
+        assert_eq!(embed_and_extract(&mut stream, "seeded", &payload), payload);
+
+        let carrier = TestImage::new(32, 32);
+        let image = stream.into_inner();
+        let changed_rows: Vec<u32> = (0..32)
+            .filter(|&y| (0..32).any(|x| image.pixel(x, y).b != carrier.pixel(x, y).b))
+            .collect();
+        assert!(changed_rows.iter().any(|&y| y > 16));
+
+        let path = temp_path("seeded_wrong.out");
+        let mut stream = ImageDataStream::new(image);
+        stream.set_seed(0xBADBAD);
+        let mut output = FileBitWriter::open(&path).unwrap();
+        let err = stream.read_stream(&mut output).unwrap_err();
+        drop(output);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            "payload checksum mismatch: corrupted or not a stego image"
+        );
+    }
+
+    #[test]
+    fn extract_bytes_returns_payload() {
+        let payload = [0xDEu8, 0xAD, 0xBE, 0xEF, 0x00, 0x01];
+        let path = temp_path("extract_bytes.in");
+        fs::write(&path, payload).unwrap();
+
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        let mut input = FileBitReader::open(&path).unwrap();
+        stream.write_stream(&mut input).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(stream.extract_bytes().unwrap(), payload);
+    }
+
+    #[test]
+    fn verify_detects_unrecoverable_payload() {
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        stream.embed_bytes(b"check me").unwrap();
+        stream.verify(b"check me").unwrap();
+        assert!(stream.verify(b"check m").is_err());
+        assert!(stream.verify(b"check mf").is_err());
+
+        let addr = ImageDataStream::<TestImage>::HEADER_WORDS + 2;
+        let word = stream.read_word(addr);
+        stream.write_word(addr, word ^ 1);
+        assert!(stream.verify(b"check me").is_err());
+    }
+
+    #[test]
+    fn text_round_trips_utf8() {
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        stream.embed_text("héllo 🌍").unwrap();
+        assert_eq!(stream.extract_text().unwrap(), "héllo 🌍");
+
+        stream.embed_bytes(&[0xff, 0xfe]).unwrap();
+        assert!(matches!(
+            stream.extract_text(),
+            Err(StegError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn streams_round_trip_without_files() {
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        stream
+            .write_stream(&mut MemBitReader::from_slice(b"no temp files"))
+            .unwrap();
+
+        let mut output = MemBitWriter::new(Vec::new());
+        stream.read_stream(&mut output).unwrap();
+        assert_eq!(output.into_bytes(), b"no temp files");
+    }
+
+    #[test]
+    fn embed_bytes_round_trips_in_memory() {
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        stream.embed_bytes(b"hello").unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"hello");
+
+        let cap = stream.capacity() as usize;
+        assert!(stream.embed_bytes(&vec![0; cap + 1]).is_err());
+    }
+
+    #[test]
+    fn rejects_length_beyond_header_field() {
+        let stream = ImageDataStream::new(TestImage::new(8, 8));
+        let err = stream.check_capacity(Header::MAX_LENGTH + 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "payload of {} bytes exceeds maximum length {}",
+                1u64 << 63,
+                Header::MAX_LENGTH
+            )
+        );
+    }
+
+    struct HugeInput;
+
+    impl Read for HugeInput {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Seek for HugeInput {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            match pos {
+                std::io::SeekFrom::End(_) => Ok(1 << 63),
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn write_stream_rejects_length_beyond_header_field() {
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        let mut input = BitReader::new(HugeInput).unwrap();
+        assert!(matches!(
+            stream.write_stream(&mut input),
+            Err(StegError::LengthOverflow { bytes, max: Header::MAX_LENGTH }) if bytes == 1 << 63
+        ));
+    }
+
+    #[test]
+    fn tiny_carrier_reports_too_small() {
+        let mut stream = ImageDataStream::new(TestImage::new(2, 2));
+        let needed = ImageDataStream::<TestImage>::HEADER_WORDS;
+        assert!(matches!(
+            stream.embed_bytes(b""),
+            Err(StegError::CarrierTooSmall { pixels: 4, needed: n }) if n == needed
+        ));
+        assert!(matches!(
+            stream.extract_bytes(),
+            Err(StegError::CarrierTooSmall { pixels: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn builder_applies_options() {
+        let mut stream = ImageDataStreamBuilder::new()
+            .bits(2, 2, 2)
+            .filename("notes.txt")
+            .password("hunter2")
+            .compress(true)
+            .seed(7)
+            .data_offset(10)
+            .build(TestImage::new(32, 32))
+            .unwrap();
+        stream.embed_bytes(&[b'z'; 300]).unwrap();
+
+        let mut decoder = ImageDataStreamBuilder::new()
+            .password("hunter2")
+            .seed(7)
+            .build(stream.into_inner())
+            .unwrap();
+        assert_eq!(
+            decoder.embedded_filename().unwrap().as_deref(),
+            Some("notes.txt")
+        );
+        assert_eq!(decoder.extract_bytes().unwrap(), [b'z'; 300]);
+
+        decoder.set_password("wrong");
+        assert_ne!(decoder.extract_bytes().ok(), Some(vec![b'z'; 300]));
+        assert!(ImageDataStreamBuilder::new()
+            .bits(4, 4, 4)
+            .build(TestImage::new(8, 8))
+            .is_err());
+    }
+
+    #[test]
+    fn with_bits_rejects_invalid_config() {
+        assert!(ImageDataStream::with_bits(TestImage::new(4, 4), 0, 2, 2).is_err());
+        assert!(ImageDataStream::with_bits(TestImage::new(4, 4), 4, 3, 2).is_err());
+    }
+
+    #[test]
+    fn heatmap_marks_header_data_and_unused_pixels() {
+        type Stream = ImageDataStream<TestImage>;
+
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        let map = stream.capacity_heatmap();
+        for addr in 0..Stream::HEADER_WORDS as u32 {
+            assert_eq!(map.pixel(addr % 16, addr / 16), Stream::HEATMAP_HEADER);
+        }
+        assert_ne!(map.pixel(15, 15), Stream::HEATMAP_UNUSED);
+
+        stream.embed_bytes(b"short").unwrap();
+        let map = stream.capacity_heatmap();
+        for addr in 0..Stream::HEADER_WORDS as u32 {
+            assert_eq!(map.pixel(addr % 16, addr / 16), Stream::HEATMAP_HEADER);
+        }
+        let data = Stream::HEADER_WORDS as u32;
+        assert_ne!(map.pixel(data % 16, data / 16), Stream::HEATMAP_HEADER);
+        assert_ne!(map.pixel(data % 16, data / 16), Stream::HEATMAP_UNUSED);
+        assert_eq!(map.pixel(15, 15), Stream::HEATMAP_UNUSED);
+    }
+
+    #[test]
+    fn fit_report_describes_usage() {
+        let stream = ImageDataStream::new(BMP::new(50, 50));
+        let capacity = (2500 - ImageDataStream::<BMP>::HEADER_WORDS) * 7 / 8;
+        assert_eq!(stream.capacity(), capacity);
+
+        let report = stream.fits(1024);
+        assert_eq!(
+            report,
+            FitReport {
+                capacity,
+                requested: 1024,
+                remaining: capacity - 1024,
+            }
+        );
+        assert!(report.fits());
+        assert_eq!(report.usage_percent().round(), 47.0);
+
+        let report = stream.fits(capacity + 1);
+        assert!(!report.fits());
+        assert_eq!(report.remaining, 0);
+    }
+
+    #[test]
+    fn inflate_rejects_output_past_limit() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&[0; 100_000]).unwrap();
+        let bomb = encoder.finish().unwrap();
+
+        type Stream = ImageDataStream<TestImage>;
+        assert_eq!(Stream::inflate(&bomb, 100_000).unwrap().len(), 100_000);
+        let err = Stream::inflate(&bomb, 99_999).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "decompressed payload exceeds limit of 99999 bytes"
+        );
+    }
+
+    #[test]
+    fn compressed_write_stream_rejects_huge_declared_size() {
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        stream.set_compression(true);
+        let mut input = MemBitReader::from_slice(b"short");
+        input.size = 1 << 60;
+
+        let err = stream.write_stream(&mut input).unwrap_err();
+        assert!(
+            matches!(err, StegError::LengthOverflow { bytes, max }
+                if bytes == 1 << 60 && max == ImageDataStream::<TestImage>::MAX_INFLATED_LEN),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn histogram_counts_every_pixel() {
+        let stream = ImageDataStream::new(BMP::new(9, 7));
+        let histogram = stream.lsb_histogram();
+
+        assert_eq!(histogram.len(), 128);
+        assert_eq!(histogram.iter().sum::<u64>(), 63);
+        assert_eq!(histogram[127], 63);
+    }
+
+    #[test]
+    fn mean_absolute_change_measures_embedding() {
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        assert_eq!(
+            stream.mean_absolute_change(&TestImage::new(8, 8)).unwrap(),
+            0.0
+        );
+
+        stream.embed_bytes(b"distortion").unwrap();
+        let change = stream.mean_absolute_change(&TestImage::new(8, 8)).unwrap();
+        assert!(change > 0.0 && change < 7.0, "{}", change);
+
+        let err = stream
+            .mean_absolute_change(&TestImage::new(8, 4))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "image dimensions differ: 8x8 vs 8x4");
+    }
+
+    #[test]
+    fn adaptive_ordering_prefers_textured_pixels() {
+        let (width, height) = (32, 16);
+        let mut image = TestImage::new(width, height);
+        let mut rng = SplitMix64::new(5);
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = image.pixel_mut(x, y);
+                if x < width / 2 {
+                    *pixel = Pixel {
+                        r: 128,
+                        g: 128,
+                        b: 128,
+                        a: 0xff,
+                    };
+                } else {
+                    let noise = rng.next_u64();
+                    (pixel.r, pixel.g, pixel.b) =
+                        (noise as u8, (noise >> 8) as u8, (noise >> 16) as u8);
+                }
+            }
+        }
+        let original: Vec<Pixel> = image.pixels().map(|(_, _, p)| p).collect();
+
+        let mut stream = ImageDataStream::with_bits(image, 4, 2, 2).unwrap();
+        stream.set_adaptive(true);
+        let payload = [0x5a; 64];
+        stream.embed_bytes(&payload).unwrap();
+
+        // Row 0 holds the header; the flat half's last column borders noise.
+        let mut changed = [0, 0];
+        for ((x, y, after), before) in stream.image.pixels().zip(&original) {
+            if y > 0 && x != width / 2 - 1 && after != *before {
+                changed[(x >= width / 2) as usize] += 1;
+            }
+        }
+        assert_eq!(changed[0], 0);
+        assert!(changed[1] > 0);
+
+        let decoder = ImageDataStream::new(stream.into_inner());
+        assert_eq!(decoder.extract_bytes().unwrap(), payload);
+
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        stream.set_adaptive(true);
+        stream.set_seed(1);
+        assert!(stream.embed_bytes(b"x").is_err());
+    }
+
+    #[test]
+    fn lsb_matching_steps_channels_by_one() {
+        let original = TestImage::new(16, 16);
+        let mut stream = ImageDataStream::with_bits(TestImage::new(16, 16), 1, 1, 1).unwrap();
+        stream.set_embed_mode(EmbedMode::MatchingLsb);
+        stream.embed_bytes(b"matching").unwrap();
+
+        // The header uses the default layout, so only data pixels step by one.
+        let header_words = stream.header_words() as usize;
+        let mut stepped = [0usize; 2];
+        let pairs = stream.image.pixels().zip(original.pixels());
+        for ((_, _, after), (_, _, before)) in pairs.skip(header_words) {
+            for (a, b) in [
+                (after.r, before.r),
+                (after.g, before.g),
+                (after.b, before.b),
+            ] {
+                assert!(a.abs_diff(b) <= 1);
+                if a != b {
+                    stepped[(a > b) as usize] += 1;
+                }
+            }
+        }
+        assert!(stepped[0] > 0 && stepped[1] > 0, "{:?}", stepped);
+
+        let decoder = ImageDataStream::new(stream.into_inner());
+        assert_eq!(decoder.extract_bytes().unwrap(), b"matching");
+    }
+
+    #[test]
+    fn blue_only_leaves_red_and_green_untouched() {
+        let payload = b"subtle";
+        let mut stream = ImageDataStream::blue_only(TestImage::new(16, 16), 2).unwrap();
+        assert_eq!(
+            embed_and_extract(&mut stream, "blue_only", payload),
+            payload
+        );
+
+        let carrier = TestImage::new(16, 16);
+        let image = stream.into_inner();
+        for (x, y, pixel) in image.pixels() {
+            let before = carrier.pixel(x, y);
+            assert_eq!((pixel.r, pixel.g), (before.r, before.g));
+            assert_eq!(pixel.b & !0b11, before.b & !0b11);
+        }
+
+        let stream = ImageDataStream::new(image);
+        assert!(stream.has_payload());
+        assert_eq!(stream.extract_bytes().unwrap(), payload);
+    }
+
+    #[test]
+    fn channel_mask_leaves_unselected_channels_untouched() {
+        let payload = b"red and blue";
+        let mut stream =
+            ImageDataStream::with_channels(TestImage::new(16, 16), CHANNEL_RED | CHANNEL_BLUE)
+                .unwrap();
+        assert_eq!(
+            embed_and_extract(&mut stream, "channel_mask", payload),
+            payload
+        );
+
+        let carrier = TestImage::new(16, 16);
+        let image = stream.into_inner();
+        for (x, y, pixel) in image.pixels() {
+            assert_eq!(pixel.g, carrier.pixel(x, y).g);
+        }
+
+        let stream = ImageDataStream::new(image);
+        assert_eq!(stream.extract_bytes().unwrap(), payload);
+    }
+
+    #[test]
+    fn green_only_mask_is_not_mistaken_for_alpha() {
+        let payload = b"green";
+        let mut stream =
+            ImageDataStream::with_channels(TestImage::new(16, 16), CHANNEL_GREEN).unwrap();
+        stream.embed_bytes(payload).unwrap();
+
+        let stream = ImageDataStream::new(stream.into_inner());
+        assert_eq!(stream.extract_bytes().unwrap(), payload);
+        assert!(ImageDataStream::with_channels(TestImage::new(4, 4), 0b1000).is_err());
+    }
+
+    #[test]
+    fn alpha_mode_requires_alpha_channel() {
+        let err = ImageDataStream::alpha_only(BMP::new(8, 8), 2)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "alpha mode requires an image with an alpha channel"
+        );
+    }
+
+    #[test]
+    fn progress_reports_completion() {
+        let payload: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let input_path = temp_path("progress.in");
+        let output_path = temp_path("progress.out");
+        fs::write(&input_path, &payload).unwrap();
+
+        let mut stream = ImageDataStream::new(TestImage::new(120, 100));
+        let mut calls = Vec::new();
+        let mut input = FileBitReader::open(&input_path).unwrap();
+        stream
+            .write_stream_with_progress(&mut input, &mut |done, total| calls.push((done, total)))
+            .unwrap();
+        assert_eq!(calls, [(4096, 10_000), (8192, 10_000), (10_000, 10_000)]);
+
+        calls.clear();
+        let mut output = FileBitWriter::open(&output_path).unwrap();
+        stream
+            .read_stream_with_progress(&mut output, &mut |done, total| calls.push((done, total)))
+            .unwrap();
+        drop(output);
+        assert_eq!(calls.last(), Some(&(10_000, 10_000)));
+        assert_eq!(calls.len(), 3);
+
+        assert_eq!(fs::read(&output_path).unwrap(), payload);
+        fs::remove_file(&input_path).unwrap();
+        fs::remove_file(&output_path).unwrap();
+    }
+
+    #[test]
+    fn embed_files_round_trips_names_and_contents() {
+        let first = temp_path("notes.txt");
+        let second = temp_path("key.bin");
+        fs::write(&first, b"meet at dawn").unwrap();
+        fs::write(&second, [0u8, 1, 2, 3, 255]).unwrap();
+
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        stream.embed_files(&[&first, &second]).unwrap();
+        let files = stream.extract_files().unwrap();
+
+        let name =
+            |path: &std::path::PathBuf| path.file_name().unwrap().to_str().unwrap().to_owned();
+        assert_eq!(
+            files,
+            [
+                (name(&first), b"meet at dawn".to_vec()),
+                (name(&second), vec![0, 1, 2, 3, 255]),
+            ]
+        );
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+
+        stream.embed_bytes(b"single").unwrap();
+        assert!(matches!(stream.extract_files(), Err(StegError::NotArchive)));
+    }
+
+    #[test]
+    fn capacity_for_matches_instance_capacity() {
+        let capacity = ImageDataStream::<BMP>::capacity_for(100, 100, 3, 2, 2).unwrap();
+        assert_eq!(capacity, (100 * 100 - 27) * 7 / 8);
+        assert_eq!(
+            capacity,
+            ImageDataStream::new(BMP::new(100, 100)).capacity()
+        );
+
+        let capacity = ImageDataStream::<BMP>::capacity_for(100, 100, 1, 1, 1).unwrap();
+        assert_eq!(capacity, (100 * 100 - 27) * 3 / 8);
+        assert!(ImageDataStream::<BMP>::capacity_for(100, 100, 4, 4, 4).is_err());
+    }
+
+    #[test]
+    fn data_offset_skips_pixels_after_header() {
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        let header_words = ImageDataStream::<TestImage>::HEADER_WORDS;
+        stream.set_data_offset(100).unwrap();
+        assert_eq!(stream.capacity(), (256 - header_words - 100) * 7 / 8);
+        let skipped = header_words..header_words + 100;
+        let before: Vec<u8> = skipped.clone().map(|addr| stream.read_word(addr)).collect();
+        stream.embed_bytes(b"lower down").unwrap();
+
+        let after: Vec<u8> = skipped.map(|addr| stream.read_word(addr)).collect();
+        assert_eq!(after, before);
+
+        let decoder = ImageDataStream::new(stream.into_inner());
+        assert_eq!(decoder.extract_bytes().unwrap(), b"lower down");
+    }
+
+    #[test]
+    fn noise_fill_covers_region_past_payload() {
+        let mut stream = ImageDataStream::new(TestImage::new(32, 32));
+        stream.set_noise_fill(0x5EED);
+        stream.embed_bytes(b"needle").unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"needle");
+
+        let (_, data_start) = stream.read_header().unwrap();
+        let tail = data_start + (6 * 8u64).div_ceil(7);
+        let carrier = ImageDataStream::new(TestImage::new(32, 32));
+        let unchanged = (tail..stream.words())
+            .filter(|&addr| stream.read_word(addr) == carrier.read_word(addr))
+            .count() as u64;
+        assert!(
+            unchanged * 20 < stream.words() - tail,
+            "{} unchanged",
+            unchanged
+        );
+    }
+
+    fn entropy(values: impl Iterator<Item = u8>) -> f64 {
+        let mut counts = [0u64; 256];
+        let mut total = 0;
+        for value in values {
+            counts[value as usize] += 1;
+            total += 1;
+        }
+        counts
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / total as f64;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn decoy_is_ignored_and_looks_random() {
+        let mut stream = ImageDataStream::new(BMP::new(64, 64));
+        stream.set_decoy(0.5).unwrap();
+        stream.embed_bytes(b"needle").unwrap();
+        assert_eq!(stream.payload_len(), 6);
+        assert_eq!(stream.extract_bytes().unwrap(), b"needle");
+
+        let (_, data_start) = stream.read_header().unwrap();
+        let decoy_start = data_start + (6 * 8u64).div_ceil(7);
+        let decoy_end = data_start + (stream.capacity() / 2 * 8).div_ceil(7);
+        let words = (decoy_start..decoy_end - 1).map(|addr| stream.read_word(addr));
+        assert!(entropy(words) > 6.5);
+
+        // Past the decoy the blank carrier is untouched.
+        assert!((decoy_end..stream.words()).all(|addr| stream.read_word(addr) == 0x7F));
+        assert!(stream.set_decoy(1.0).is_err());
+    }
+
+    #[test]
+    fn image_accessors_allow_writing_without_consuming() {
+        let path = temp_path("image_accessors.bmp");
+        let mut stream = ImageDataStream::new(BMP::new(16, 16));
+        stream.embed_bytes(b"kept").unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"kept");
+
+        stream.image().write(&path).unwrap();
+        let written = ImageDataStream::new(BMP::read(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written.extract_bytes().unwrap(), b"kept");
+
+        stream.image_mut().pixel_mut(0, 0).r ^= 0b111;
+        assert!(stream.extract_bytes().is_err());
+    }
+
+    #[test]
+    fn hmac_detects_tampered_payload() {
+        let mut stream = ImageDataStream::new(BMP::new(32, 32));
+        stream.set_auth_key("signing key");
+        stream.embed_bytes(b"authentic").unwrap();
+
+        let mut output = MemBitWriter::new(Vec::new());
+        stream.read_stream(&mut output).unwrap();
+        assert_eq!(output.into_bytes(), b"authentic");
+
+        let copy = BMP::from_bytes(&stream.image().to_bytes().unwrap()).unwrap();
+        let unkeyed = ImageDataStream::new(copy);
+        assert!(matches!(
+            unkeyed.extract_bytes(),
+            Err(StegError::AuthKeyRequired)
+        ));
+
+        let (_, data_start) = stream.read_header().unwrap();
+        stream.write_word(data_start, stream.read_word(data_start) ^ 1);
+        let mut output = MemBitWriter::new(Vec::new());
+        assert!(matches!(
+            stream.read_stream(&mut output),
+            Err(StegError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn stream_over_boxed_image() {
+        let images: Vec<Box<dyn Image>> =
+            vec![Box::new(TestImage::new(8, 8)), Box::new(BMP::new(8, 8))];
+        for image in images {
+            let mut stream: DynImageDataStream = ImageDataStream::new(image);
+            stream.embed_bytes(b"dynamic").unwrap();
+            assert_eq!(stream.extract_bytes().unwrap(), b"dynamic");
+        }
+    }
+
+    #[test]
+    fn stray_bits_in_final_word_are_ignored() {
+        let payload = b"tail!";
+        let mut stream = ImageDataStream::new(TestImage::new(8, 8));
+        stream.embed_bytes(payload).unwrap();
+
+        // 40 payload bits leave the top two bits of the sixth word unused.
+        let (_, data_start) = stream.read_header().unwrap();
+        let last = data_start + 5;
+        let word = stream.read_word(last);
+        stream.write_word(last, word | 0b110_0000);
+
+        assert_eq!(stream.extract_bytes().unwrap(), payload);
+    }
+
+    #[test]
+    fn sanitize_destroys_payload() {
+        for fill in [Fill::Zero, Fill::Random(42)] {
+            let mut stream = ImageDataStream::with_bits(TestImage::new(8, 8), 1, 1, 1).unwrap();
+            stream.embed_bytes(b"evidence").unwrap();
+            assert!(stream.has_payload());
+
+            stream.sanitize(fill);
+            assert!(!stream.has_payload());
+
+            let mut stream = ImageDataStream::alpha_only(TestImage::with_alpha(16, 16), 2).unwrap();
+            stream.embed_bytes(b"evidence").unwrap();
+
+            let mut stream = ImageDataStream::new(stream.into_inner());
+            assert!(stream.has_payload());
+            stream.sanitize(fill);
+            assert!(!stream.has_payload());
+            assert_ne!(
+                stream.extract_bytes().ok().as_deref(),
+                Some(&b"evidence"[..])
+            );
+        }
+    }
+
+    #[test]
+    fn clear_before_write_leaves_no_residue() {
+        let mut stream = ImageDataStream::new(TestImage::new(16, 16));
+        stream.embed_bytes(&[0xFF; 100]).unwrap();
+
+        stream.set_clear_before_write(true);
+        stream.embed_bytes(b"short").unwrap();
+        assert_eq!(stream.extract_bytes().unwrap(), b"short");
+
+        let (_, data_start) = stream.read_header().unwrap();
+        let used = (5 * 8u64).div_ceil(7);
+        for addr in data_start + used..stream.words() {
+            assert_eq!(stream.read_word(addr), 0, "word {}", addr);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_embedding_matches_sequential() {
+        let payload: Vec<u8> = (0..=255).collect();
+        // Spans several write-back chunks.
+        let mut sequential = ImageDataStream::new(TestImage::new(256, 192));
+        let mut parallel = ImageDataStream::new(TestImage::new(256, 192));
+        for stream in [&mut sequential, &mut parallel] {
+            stream.set_seed(7);
+            stream.set_filename("parallel").unwrap();
+        }
+
+        sequential.embed_bytes(&payload).unwrap();
+        parallel.par_embed_bytes(&payload).unwrap();
+        assert_eq!(parallel.par_extract_bytes().unwrap(), payload);
+
+        let (a, b) = (sequential.into_inner(), parallel.into_inner());
+        assert!(a.pixels().zip(b.pixels()).all(|(a, b)| a == b));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_embedding_without_contiguous_pixels_matches_sequential() {
+        let sparse = || SparseImage {
+            width: 64,
+            height: 64,
+            blank: Pixel {
+                r: 0x80,
+                g: 0x80,
+                b: 0x80,
+                a: 0,
+            },
+            pixels: Default::default(),
+        };
+        let mut sequential = ImageDataStream::new(sparse());
+        let mut parallel = ImageDataStream::new(sparse());
+        sequential.set_seed(3);
+        parallel.set_seed(3);
+
+        sequential.embed_bytes(b"no slice").unwrap();
+        parallel.par_embed_bytes(b"no slice").unwrap();
+        assert_eq!(parallel.extract_bytes().unwrap(), b"no slice");
+
+        let (a, b) = (sequential.into_inner(), parallel.into_inner());
+        assert!(a.pixels().zip(b.pixels()).all(|(a, b)| a == b));
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use crate::image::{Image, Pixel};
+
+pub struct TestImage {
+    width: u32,
+    height: u32,
+    alpha: bool,
+    pixels: Vec<Pixel>,
+}
+
+impl TestImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        let pixels = (0..width * height)
+            .map(|i| Pixel {
+                r: i as u8,
+                g: (i * 3) as u8,
+                b: (i * 7) as u8,
+                a: 0xFF,
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            alpha: false,
+            pixels,
+        }
+    }
+
+    pub fn with_alpha(width: u32, height: u32) -> Self {
+        Self {
+            alpha: true,
+            ..Self::new(width, height)
+        }
+    }
+}
+
+impl Image for TestImage {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> Pixel {
+        self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    fn pixel_mut(&mut self, x: u32, y: u32) -> &mut Pixel {
+        &mut self.pixels[y as usize * self.width as usize + x as usize]
+    }
+
+    fn has_alpha(&self) -> bool {
+        self.alpha
+    }
+
+    fn contiguous_pixels_mut(&mut self) -> Option<&mut [Pixel]> {
+        Some(&mut self.pixels)
+    }
+}
+
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("lab2-{}-{}", std::process::id(), name))
+}
@@ -0,0 +1,101 @@
+use lab2::BMP;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lab2-cli-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn lab2() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_lab2"))
+}
+
+#[test]
+fn embed_then_extract() {
+    let dir = temp_dir("round_trip");
+    let carrier = dir.join("carrier.bmp");
+    let payload = dir.join("secret.txt");
+    let stego = dir.join("stego.bmp");
+    let extracted = dir.join("extracted.txt");
+
+    BMP::new(64, 64).write(&carrier).unwrap();
+    fs::write(&payload, b"meet me at the usual place").unwrap();
+
+    let status = lab2()
+        .arg("embed")
+        .arg("--carrier")
+        .arg(&carrier)
+        .arg("--payload")
+        .arg(&payload)
+        .arg("--out")
+        .arg(&stego)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = lab2()
+        .arg("extract")
+        .arg("--carrier")
+        .arg(&stego)
+        .arg("--out")
+        .arg(&extracted)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "secret.txt");
+    assert_eq!(fs::read(&extracted).unwrap(), fs::read(&payload).unwrap());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn embed_fails_when_payload_exceeds_capacity() {
+    let dir = temp_dir("too_large");
+    let carrier = dir.join("carrier.bmp");
+    let payload = dir.join("large.bin");
+    let stego = dir.join("stego.bmp");
+
+    BMP::new(8, 8).write(&carrier).unwrap();
+    fs::write(&payload, vec![0u8; 1024]).unwrap();
+
+    let output = lab2()
+        .arg("embed")
+        .arg("--carrier")
+        .arg(&carrier)
+        .arg("--payload")
+        .arg(&payload)
+        .arg("--out")
+        .arg(&stego)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("exceeds capacity"));
+    assert!(!stego.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failed_extract_leaves_no_output() {
+    let dir = temp_dir("no_payload");
+    let carrier = dir.join("carrier.bmp");
+    let extracted = dir.join("extracted.txt");
+
+    BMP::new(16, 16).write(&carrier).unwrap();
+
+    let output = lab2()
+        .arg("extract")
+        .arg("--carrier")
+        .arg(&carrier)
+        .arg("--out")
+        .arg(&extracted)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!extracted.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}